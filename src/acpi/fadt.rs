@@ -0,0 +1,180 @@
+use acpi::sdt::{Sdt, GenericAddress, read_u8, read_u16, read_u32, read_u64};
+use memory::PhysicalAddress;
+
+//Fixed ACPI Description Table - the power management registers and where the DSDT is
+// http://wiki.osdev.org/FADT
+
+//Length of an ACPI 1.0 FADT - everything after it is only read when the table is long enough
+const FADT_V1_SIZE: usize = 116;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+
+//IA-PC boot architecture flags
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+//Fixed feature flags
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+	pub dsdt_address: PhysicalAddress,
+	pub sci_interrupt: u16,
+	//writing acpi_enable here hands the power management events from the firmware to the OS
+	pub smi_command_port: u32,
+	pub acpi_enable: u8,
+	pub acpi_disable: u8,
+	pub pm1a_event_block: u32,
+	pub pm1b_event_block: u32,
+	pub pm1a_control_block: u32,
+	pub pm1b_control_block: u32,
+	pub pm_timer_block: u32,
+	pub pm1_event_length: u8,
+	pub pm1_control_length: u8,
+	pub pm_timer_length: u8,
+	//CMOS register holding the century, 0 if there isn't one
+	pub century_register: u8,
+	pub boot_architecture_flags: u16,
+	pub flags: u32,
+	//only there from ACPI 2.0 on and only usable if reset_supported()
+	pub reset_register: Option<GenericAddress>,
+	pub reset_value: u8
+}
+
+impl Fadt {
+	pub fn parse(table: &Sdt) -> Result<Fadt, &'static str> {
+		let bytes = table.bytes();
+		if bytes.len() < FADT_V1_SIZE {
+			return Err("FADT too short");
+		}
+		let mut fadt = Fadt {
+			dsdt_address: read_u32(bytes, 40) as PhysicalAddress,
+			sci_interrupt: read_u16(bytes, 46),
+			smi_command_port: read_u32(bytes, 48),
+			acpi_enable: read_u8(bytes, 52),
+			acpi_disable: read_u8(bytes, 53),
+			pm1a_event_block: read_u32(bytes, 56),
+			pm1b_event_block: read_u32(bytes, 60),
+			pm1a_control_block: read_u32(bytes, 64),
+			pm1b_control_block: read_u32(bytes, 68),
+			pm_timer_block: read_u32(bytes, 76),
+			pm1_event_length: read_u8(bytes, 88),
+			pm1_control_length: read_u8(bytes, 89),
+			pm_timer_length: read_u8(bytes, 91),
+			century_register: read_u8(bytes, 108),
+			boot_architecture_flags: 0,
+			flags: read_u32(bytes, 112),
+			reset_register: None,
+			reset_value: 0
+		};
+		//ACPI 1.0 has no boot architecture flags and the field is reserved
+		if table.header.revision >= 2 {
+			fadt.boot_architecture_flags = read_u16(bytes, 109);
+		}
+		if bytes.len() > RESET_VALUE {
+			fadt.reset_register = Some(GenericAddress::read(bytes, RESET_REGISTER));
+			fadt.reset_value = read_u8(bytes, RESET_VALUE);
+		}
+		//the 64 bit X_DSDT wins over DSDT when it is set
+		if bytes.len() >= X_DSDT + 8 {
+			let x_dsdt = read_u64(bytes, X_DSDT);
+			if x_dsdt != 0 {
+				fadt.dsdt_address = x_dsdt as PhysicalAddress;
+			}
+		}
+		Ok(fadt)
+	}
+
+	pub fn has_legacy_devices(&self) -> bool {
+		self.boot_architecture_flags & BOOT_ARCH_LEGACY_DEVICES != 0
+	}
+
+	pub fn has_8042(&self) -> bool {
+		self.boot_architecture_flags & BOOT_ARCH_8042 != 0
+	}
+
+	pub fn vga_present(&self) -> bool {
+		self.boot_architecture_flags & BOOT_ARCH_VGA_NOT_PRESENT == 0
+	}
+
+	//The PM timer counts at 3.579545 MHz with 24 bits, or 32 bits if this is set
+	pub fn pm_timer_32bit(&self) -> bool {
+		self.flags & FLAG_TMR_VAL_EXT != 0
+	}
+
+	pub fn reset_supported(&self) -> bool {
+		self.flags & FLAG_RESET_REG_SUP != 0 && self.reset_register.is_some()
+	}
+
+	pub fn hardware_reduced(&self) -> bool {
+		self.flags & FLAG_HW_REDUCED_ACPI != 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use acpi::sdt::{test_table, SDT_HEADER_SIZE};
+
+	//Writes bytes into a table body at offset, which counts from the start of the table like the
+	//specification does
+	fn put(body: &mut [u8], offset: usize, bytes: &[u8]) {
+		let start = offset - SDT_HEADER_SIZE;
+		body[start..start + bytes.len()].copy_from_slice(bytes);
+	}
+
+	//The ACPI 1.0 part of QEMU's FADT
+	fn fadt_v1_body() -> Vec<u8> {
+		let mut body = vec![0; 116 - SDT_HEADER_SIZE];
+		put(&mut body, 40, &[0x40, 0x00, 0xfe, 0x07]);//DSDT
+		put(&mut body, 46, &[9, 0]);//SCI interrupt
+		put(&mut body, 48, &[0xb2, 0, 0, 0]);//SMI command port
+		put(&mut body, 52, &[0xf1, 0xf0]);//ACPI enable and disable values
+		put(&mut body, 56, &[0x00, 0x06, 0, 0]);//PM1a event block
+		put(&mut body, 64, &[0x04, 0x06, 0, 0]);//PM1a control block
+		put(&mut body, 76, &[0x08, 0x06, 0, 0]);//PM timer block
+		put(&mut body, 88, &[4, 2, 0, 4]);//PM1 event, PM1 control, PM2 control and PM timer lengths
+		put(&mut body, 108, &[0x32]);//century register
+		put(&mut body, 112, &[0xa5, 0x05, 0, 0]);//flags, with TMR_VAL_EXT and RESET_REG_SUP
+		body
+	}
+
+	#[test]
+	fn parses_acpi_2_fadt() {
+		let mut body = fadt_v1_body();
+		body.resize(244 - SDT_HEADER_SIZE, 0);
+		put(&mut body, 109, &[0x03, 0x00]);//boot architecture flags: legacy devices and an 8042
+		put(&mut body, 116, &[1, 8, 0, 1, 0xf9, 0x0c, 0, 0, 0, 0, 0, 0]);//reset register: I/O port 0xcf9
+		put(&mut body, 128, &[0x0f]);//reset value
+		put(&mut body, 140, &[0x40, 0x00, 0xfe, 0x07, 0, 0, 0, 0]);//X_DSDT
+
+		let fadt = Fadt::parse(&test_table(b"FACP", 3, &body)).unwrap();
+		assert_eq!(fadt.dsdt_address, 0x7fe_0040);
+		assert_eq!(fadt.sci_interrupt, 9);
+		assert_eq!((fadt.smi_command_port, fadt.acpi_enable, fadt.acpi_disable), (0xb2, 0xf1, 0xf0));
+		assert_eq!((fadt.pm1a_event_block, fadt.pm1a_control_block, fadt.pm_timer_block), (0x600, 0x604, 0x608));
+		assert_eq!((fadt.pm1_event_length, fadt.pm1_control_length, fadt.pm_timer_length), (4, 2, 4));
+		assert_eq!(fadt.century_register, 0x32);
+		assert!(fadt.has_legacy_devices() && fadt.has_8042() && fadt.vga_present());
+		assert!(fadt.pm_timer_32bit());
+		assert!(!fadt.hardware_reduced());
+
+		assert!(fadt.reset_supported());
+		let reset = fadt.reset_register.unwrap();
+		assert_eq!((reset.address_space, reset.bit_width, reset.address), (1, 8, 0xcf9));
+		assert_eq!(fadt.reset_value, 0x0f);
+	}
+
+	#[test]
+	fn parses_acpi_1_fadt() {
+		let fadt = Fadt::parse(&test_table(b"FACP", 1, &fadt_v1_body())).unwrap();
+		assert_eq!(fadt.dsdt_address, 0x7fe_0040);
+		assert_eq!(fadt.boot_architecture_flags, 0);
+		assert!(fadt.reset_register.is_none());
+		assert!(!fadt.reset_supported());
+	}
+}
@@ -0,0 +1,79 @@
+use acpi::sdt::{Sdt, GenericAddress, GENERIC_ADDRESS_SIZE, read_u8, read_u16, read_u32};
+
+//High Precision Event Timer description
+// http://wiki.osdev.org/HPET
+
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = BASE_ADDRESS + GENERIC_ADDRESS_SIZE;
+const HPET_SIZE: usize = 56;
+
+const COUNTER_64BIT: u32 = 1 << 13;
+const LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+	pub hardware_revision: u8,
+	pub comparator_count: u8,
+	pub counter_64bit: bool,
+	//can take over the PIT's IRQ 0 and the RTC's IRQ 8
+	pub legacy_replacement: bool,
+	pub pci_vendor_id: u16,
+	pub base_address: GenericAddress,
+	pub hpet_number: u8,
+	//smallest periodic tick that doesn't lose interrupts, in main counter ticks
+	pub minimum_tick: u16
+}
+
+impl Hpet {
+	pub fn parse(table: &Sdt) -> Result<Hpet, &'static str> {
+		let bytes = table.bytes();
+		if bytes.len() < HPET_SIZE {
+			return Err("HPET table too short");
+		}
+		let id = read_u32(bytes, EVENT_TIMER_BLOCK_ID);
+		Ok(Hpet {
+			hardware_revision: id as u8,
+			comparator_count: (((id >> 8) & 0x1F) + 1) as u8,
+			counter_64bit: id & COUNTER_64BIT != 0,
+			legacy_replacement: id & LEGACY_REPLACEMENT != 0,
+			pci_vendor_id: (id >> 16) as u16,
+			base_address: GenericAddress::read(bytes, BASE_ADDRESS),
+			hpet_number: read_u8(bytes, HPET_NUMBER),
+			minimum_tick: read_u16(bytes, HPET_NUMBER + 1)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use acpi::sdt::test_table;
+
+	static HPET_BODY: [u8; 20] = [
+		//event timer block id: revision 1, 3 comparators, 64 bit counter, legacy replacement, vendor 0x8086
+		0x01, 0xa2, 0x86, 0x80,
+		//base address: system memory, 64 bits wide, offset 0, access size 0, 0xfed00000
+		0, 64, 0, 0, 0x00, 0x00, 0xd0, 0xfe, 0x00, 0x00, 0x00, 0x00,
+		0,//HPET number
+		0x80, 0x00,//minimum tick
+		0,//page protection
+	];
+
+	#[test]
+	fn parses_hpet() {
+		let hpet = Hpet::parse(&test_table(b"HPET", 1, &HPET_BODY)).unwrap();
+		assert_eq!(hpet.hardware_revision, 1);
+		assert_eq!(hpet.comparator_count, 3);
+		assert!(hpet.counter_64bit && hpet.legacy_replacement);
+		assert_eq!(hpet.pci_vendor_id, 0x8086);
+		assert_eq!((hpet.base_address.address_space, hpet.base_address.address), (0, 0xfed0_0000));
+		assert_eq!(hpet.hpet_number, 0);
+		assert_eq!(hpet.minimum_tick, 0x80);
+	}
+
+	#[test]
+	fn rejects_short_hpet() {
+		assert!(Hpet::parse(&test_table(b"HPET", 1, &HPET_BODY[0..19])).is_err());
+	}
+}
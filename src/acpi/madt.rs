@@ -0,0 +1,234 @@
+use alloc::vec::Vec;
+use acpi::sdt::{Sdt, read_u8, read_u16, read_u32, read_u64};
+use memory::PhysicalAddress;
+
+//Multiple APIC Description Table - the CPUs, I/O APICs and how the ISA IRQs are wired to them
+// http://wiki.osdev.org/MADT
+
+const MADT_ENTRIES: usize = 44;
+const MADT_FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+//MPS INTI flags of overrides and NMIs - 0 in either field means "as the bus does it"
+const POLARITY_MASK: u16 = 0x3;
+const POLARITY_ACTIVE_LOW: u16 = 0x3;
+const TRIGGER_MASK: u16 = 0x3 << 2;
+const TRIGGER_LEVEL: u16 = 0x3 << 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Processor {
+	pub processor_id: u32,
+	pub apic_id: u32,
+	pub enabled: bool,
+	//a disabled processor that can still be brought online
+	pub online_capable: bool
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApicInfo {
+	pub id: u8,
+	pub address: PhysicalAddress,
+	//global system interrupt of its first input
+	pub gsi_base: u32
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+	pub bus: u8,
+	pub irq: u8,
+	pub gsi: u32,
+	pub flags: u16
+}
+
+impl InterruptOverride {
+	//ISA interrupts are active high unless overridden
+	pub fn active_low(&self) -> bool {
+		self.flags & POLARITY_MASK == POLARITY_ACTIVE_LOW
+	}
+
+	//ISA interrupts are edge triggered unless overridden
+	pub fn level_triggered(&self) -> bool {
+		self.flags & TRIGGER_MASK == TRIGGER_LEVEL
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicNmi {
+	//0xFF means every processor
+	pub processor_id: u8,
+	pub flags: u16,
+	pub lint: u8
+}
+
+#[derive(Debug)]
+pub struct Madt {
+	pub local_apic_address: PhysicalAddress,
+	//the legacy 8259s are present and have to be masked before using the APIC
+	pub pcat_compatible: bool,
+	pub processors: Vec<Processor>,
+	pub io_apics: Vec<IoApicInfo>,
+	pub interrupt_overrides: Vec<InterruptOverride>,
+	pub local_apic_nmis: Vec<LocalApicNmi>
+}
+
+impl Madt {
+	pub fn parse(table: &Sdt) -> Result<Madt, &'static str> {
+		let bytes = table.bytes();
+		if bytes.len() < MADT_ENTRIES {
+			return Err("MADT too short");
+		}
+		let mut madt = Madt {
+			local_apic_address: read_u32(bytes, 36) as PhysicalAddress,
+			pcat_compatible: read_u32(bytes, 40) & MADT_FLAG_PCAT_COMPAT != 0,
+			processors: Vec::new(),
+			io_apics: Vec::new(),
+			interrupt_overrides: Vec::new(),
+			local_apic_nmis: Vec::new()
+		};
+
+		let mut offset = MADT_ENTRIES;
+		while offset + 2 <= bytes.len() {
+			let entry_type = read_u8(bytes, offset);
+			let length = read_u8(bytes, offset + 1) as usize;
+			if length < 2 || offset + length > bytes.len() {
+				return Err("MADT entry runs past the end of the table");
+			}
+			let entry = &bytes[offset..offset + length];
+			match entry_type {
+				ENTRY_LOCAL_APIC if length >= 8 => {
+					let flags = read_u32(entry, 4);
+					madt.processors.push(Processor {
+						processor_id: read_u8(entry, 2) as u32,
+						apic_id: read_u8(entry, 3) as u32,
+						enabled: flags & PROCESSOR_ENABLED != 0,
+						online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0
+					});
+				},
+				ENTRY_IO_APIC if length >= 12 => {
+					madt.io_apics.push(IoApicInfo {
+						id: read_u8(entry, 2),
+						address: read_u32(entry, 4) as PhysicalAddress,
+						gsi_base: read_u32(entry, 8)
+					});
+				},
+				ENTRY_INTERRUPT_OVERRIDE if length >= 10 => {
+					madt.interrupt_overrides.push(InterruptOverride {
+						bus: read_u8(entry, 2),
+						irq: read_u8(entry, 3),
+						gsi: read_u32(entry, 4),
+						flags: read_u16(entry, 8)
+					});
+				},
+				ENTRY_LOCAL_APIC_NMI if length >= 6 => {
+					madt.local_apic_nmis.push(LocalApicNmi {
+						processor_id: read_u8(entry, 2),
+						flags: read_u16(entry, 3),
+						lint: read_u8(entry, 5)
+					});
+				},
+				ENTRY_LOCAL_APIC_ADDRESS if length >= 12 => {
+					madt.local_apic_address = read_u64(entry, 4) as PhysicalAddress;
+				},
+				ENTRY_LOCAL_X2APIC if length >= 16 => {
+					let flags = read_u32(entry, 8);
+					madt.processors.push(Processor {
+						processor_id: read_u32(entry, 12),
+						apic_id: read_u32(entry, 4),
+						enabled: flags & PROCESSOR_ENABLED != 0,
+						online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0
+					});
+				},
+				_ => {}
+			}
+			offset += length;
+		}
+		Ok(madt)
+	}
+
+	//The I/O APIC whose inputs include gsi
+	pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApicInfo> {
+		//the MADT doesn't give the input count - take the closest base at or below gsi
+		self.io_apics.iter().filter(|io_apic| io_apic.gsi_base <= gsi).max_by_key(|io_apic| io_apic.gsi_base)
+	}
+
+	pub fn interrupt_override(&self, irq: u8) -> Option<&InterruptOverride> {
+		self.interrupt_overrides.iter().find(|o| o.bus == 0 && o.irq == irq)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use acpi::sdt::test_table;
+
+	//What QEMU gives a 4 CPU machine, plus an x2APIC entry for a CPU that can be hotplugged
+	static MADT_BODY: [u8; 124] = [
+		0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00,//local APIC address 0xfee00000, PCAT_COMPAT
+		//local APICs: type, length, processor id, APIC id, flags (enabled)
+		0, 8, 0, 0, 0x01, 0x00, 0x00, 0x00,
+		0, 8, 1, 1, 0x01, 0x00, 0x00, 0x00,
+		0, 8, 2, 2, 0x01, 0x00, 0x00, 0x00,
+		0, 8, 3, 3, 0x01, 0x00, 0x00, 0x00,
+		//I/O APIC: type, length, id, reserved, address 0xfec00000, GSI base 0
+		1, 12, 0, 0, 0x00, 0x00, 0xc0, 0xfe, 0x00, 0x00, 0x00, 0x00,
+		//overrides: type, length, bus, IRQ, GSI, flags
+		2, 10, 0, 0, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,//IRQ 0 on GSI 2, as the bus does it
+		2, 10, 0, 5, 0x05, 0x00, 0x00, 0x00, 0x0d, 0x00,//active high, level triggered
+		2, 10, 0, 9, 0x09, 0x00, 0x00, 0x00, 0x0d, 0x00,//active high, level triggered
+		2, 10, 0, 10, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x00,//active high, level triggered
+		2, 10, 0, 11, 0x0b, 0x00, 0x00, 0x00, 0x0f, 0x00,//active low, level triggered
+		//local APIC NMI: type, length, every processor, flags, LINT1
+		4, 6, 0xff, 0x00, 0x00, 1,
+		//local x2APIC: type, length, reserved, APIC id 0x100, flags (online capable), processor id 4
+		9, 16, 0, 0, 0x00, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+	];
+
+	#[test]
+	fn parses_entries() {
+		let madt = Madt::parse(&test_table(b"APIC", 1, &MADT_BODY)).unwrap();
+		assert_eq!(madt.local_apic_address, 0xfee0_0000);
+		assert!(madt.pcat_compatible);
+
+		assert_eq!(madt.processors.len(), 5);
+		assert!(madt.processors[0..4].iter().enumerate()
+			.all(|(i, p)| p.processor_id == i as u32 && p.apic_id == i as u32 && p.enabled));
+		let hotplug = madt.processors[4];
+		assert_eq!((hotplug.processor_id, hotplug.apic_id), (4, 0x100));
+		assert!(!hotplug.enabled && hotplug.online_capable);
+
+		assert_eq!(madt.io_apics.len(), 1);
+		assert_eq!(madt.io_apics[0].address, 0xfec0_0000);
+		assert_eq!(madt.io_apic_for(23).map(|io_apic| io_apic.gsi_base), Some(0));
+
+		assert_eq!(madt.interrupt_overrides.len(), 5);
+		let timer = madt.interrupt_override(0).unwrap();
+		assert_eq!(timer.gsi, 2);
+		assert!(!timer.active_low() && !timer.level_triggered());
+		let sci = madt.interrupt_override(9).unwrap();
+		assert!(!sci.active_low() && sci.level_triggered());
+		let irq11 = madt.interrupt_override(11).unwrap();
+		assert!(irq11.active_low() && irq11.level_triggered());
+		assert!(madt.interrupt_override(1).is_none());
+
+		assert_eq!(madt.local_apic_nmis.len(), 1);
+		assert_eq!((madt.local_apic_nmis[0].processor_id, madt.local_apic_nmis[0].lint), (0xff, 1));
+	}
+
+	#[test]
+	fn rejects_truncated_entry() {
+		let mut body = MADT_BODY.to_vec();
+		//the x2APIC entry at the end now runs past the end of the table
+		let last = body.len() - 16;
+		body[last + 1] = 17;
+		assert!(Madt::parse(&test_table(b"APIC", 1, &body)).is_err());
+	}
+}
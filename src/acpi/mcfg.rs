@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+use acpi::sdt::{Sdt, read_u8, read_u16, read_u64};
+use memory::PhysicalAddress;
+
+//PCI Express memory mapped configuration space - one entry per segment group and bus range
+// http://wiki.osdev.org/PCI_Express
+
+const MCFG_ENTRIES: usize = 44;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+	//configuration space of bus start_bus, the other buses follow 1 MiB apart
+	pub base_address: PhysicalAddress,
+	pub segment: u16,
+	pub start_bus: u8,
+	pub end_bus: u8
+}
+
+impl McfgEntry {
+	//Physical address of the 4 KiB configuration space of a function
+	pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysicalAddress> {
+		if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+			return None;
+		}
+		Some(self.base_address + (((bus - self.start_bus) as usize) << 20 | (device as usize) << 15 | (function as usize) << 12))
+	}
+}
+
+#[derive(Debug)]
+pub struct Mcfg {
+	pub entries: Vec<McfgEntry>
+}
+
+impl Mcfg {
+	pub fn parse(table: &Sdt) -> Result<Mcfg, &'static str> {
+		let bytes = table.bytes();
+		if bytes.len() < MCFG_ENTRIES {
+			return Err("MCFG too short");
+		}
+		let mut entries = Vec::new();
+		let mut offset = MCFG_ENTRIES;
+		while offset + MCFG_ENTRY_SIZE <= bytes.len() {
+			entries.push(McfgEntry {
+				base_address: read_u64(bytes, offset) as PhysicalAddress,
+				segment: read_u16(bytes, offset + 8),
+				start_bus: read_u8(bytes, offset + 10),
+				end_bus: read_u8(bytes, offset + 11)
+			});
+			offset += MCFG_ENTRY_SIZE;
+		}
+		Ok(Mcfg { entries: entries })
+	}
+
+	pub fn entry_for(&self, segment: u16, bus: u8) -> Option<&McfgEntry> {
+		self.entries.iter().find(|e| e.segment == segment && bus >= e.start_bus && bus <= e.end_bus)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use acpi::sdt::test_table;
+
+	static MCFG_BODY: [u8; 24] = [
+		0, 0, 0, 0, 0, 0, 0, 0,//reserved
+		//base address 0xb0000000, segment 0, buses 0-255, reserved
+		0x00, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0, 0, 0, 0,
+	];
+
+	#[test]
+	fn parses_entries() {
+		let mcfg = Mcfg::parse(&test_table(b"MCFG", 1, &MCFG_BODY)).unwrap();
+		assert_eq!(mcfg.entries.len(), 1);
+		let entry = mcfg.entry_for(0, 3).unwrap();
+		assert_eq!((entry.base_address, entry.start_bus, entry.end_bus), (0xb000_0000, 0, 0xff));
+		assert_eq!(entry.function_address(3, 2, 1), Some(0xb030_0000 + (2 << 15) + (1 << 12)));
+		assert_eq!(entry.function_address(0, 32, 0), None);
+		assert!(mcfg.entry_for(1, 0).is_none());
+	}
+}
@@ -0,0 +1,135 @@
+mod sdt;
+mod rsdp;
+mod madt;
+mod fadt;
+mod hpet;
+mod mcfg;
+
+pub use self::sdt::{SdtHeader, GenericAddress};
+pub use self::rsdp::Rsdp;
+pub use self::madt::{Madt, Processor, IoApicInfo, InterruptOverride, LocalApicNmi};
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+pub use self::mcfg::{Mcfg, McfgEntry};
+use self::sdt::{Sdt, SDT_HEADER_SIZE, read_u32, read_u64};
+use alloc::vec::Vec;
+use memory::PhysicalAddress;
+
+//ACPI tables found through the RSDP. Tables the kernel doesn't understand are only listed in `tables`.
+// http://wiki.osdev.org/ACPI
+pub struct Acpi {
+	pub rsdp: Rsdp,
+	pub tables: Vec<SdtHeader>,
+	pub madt: Option<Madt>,
+	pub fadt: Option<Fadt>,
+	pub hpet: Option<Hpet>,
+	pub mcfg: Option<Mcfg>
+}
+
+//Written once by init_acpi before the other CPUs or interrupts could look at it
+static mut ACPI: Option<Acpi> = None;
+
+//Needs the heap and the MMIO window, so it runs after init_memory
+pub fn init_acpi(multiboot_information_address: PhysicalAddress) {
+	match load_acpi(multiboot_information_address) {
+		Ok(acpi) => {
+			unsafe { ACPI = Some(acpi); }
+			print_acpi_tables();
+		},
+		Err(err) => println!("ACPI: {}", err)
+	}
+}
+
+//None when no valid RSDP was found
+pub fn tables() -> Option<&'static Acpi> {
+	unsafe { ACPI.as_ref() }
+}
+
+pub fn madt() -> Option<&'static Madt> {
+	tables().and_then(|acpi| acpi.madt.as_ref())
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+	tables().and_then(|acpi| acpi.fadt.as_ref())
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+	tables().and_then(|acpi| acpi.hpet.as_ref())
+}
+
+pub fn mcfg() -> Option<&'static Mcfg> {
+	tables().and_then(|acpi| acpi.mcfg.as_ref())
+}
+
+fn load_acpi(multiboot_information_address: PhysicalAddress) -> Result<Acpi, &'static str> {
+	let rsdp = try!(rsdp::find_rsdp(multiboot_information_address).ok_or("no RSDP found"));
+
+	//the XSDT holds 64 bit pointers, the RSDT 32 bit ones
+	let (root_address, pointer_size, signature) = match rsdp.xsdt_address {
+		Some(address) if address != 0 => (address as PhysicalAddress, 8, b"XSDT"),
+		_ => (rsdp.rsdt_address as PhysicalAddress, 4, b"RSDT")
+	};
+	let root = try!(unsafe { Sdt::load(root_address) });
+	if &root.header.signature != signature {
+		return Err("root table has the wrong signature");
+	}
+
+	let mut acpi = Acpi {
+		rsdp: rsdp,
+		tables: Vec::new(),
+		madt: None,
+		fadt: None,
+		hpet: None,
+		mcfg: None
+	};
+	let bytes = root.bytes();
+	let mut offset = SDT_HEADER_SIZE;
+	while offset + pointer_size <= bytes.len() {
+		let address = if pointer_size == 8 {
+			read_u64(bytes, offset) as PhysicalAddress
+		} else {
+			read_u32(bytes, offset) as PhysicalAddress
+		};
+		offset += pointer_size;
+
+		let table = match unsafe { Sdt::load(address) } {
+			Ok(table) => table,
+			Err(err) => {
+				println!("ACPI: skipping table at {:#x}: {}", address, err);
+				continue;
+			}
+		};
+		let parsed = match &table.header.signature {
+			b"APIC" => Madt::parse(&table).map(|madt| acpi.madt = Some(madt)),
+			b"FACP" => Fadt::parse(&table).map(|fadt| acpi.fadt = Some(fadt)),
+			b"HPET" => Hpet::parse(&table).map(|hpet| acpi.hpet = Some(hpet)),
+			b"MCFG" => Mcfg::parse(&table).map(|mcfg| acpi.mcfg = Some(mcfg)),
+			_ => Ok(())
+		};
+		if let Err(err) = parsed {
+			println!("ACPI: {}: {}", table.header.signature(), err);
+		}
+		acpi.tables.push(table.header);
+	}
+	Ok(acpi)
+}
+
+pub fn print_acpi_tables() {
+	let acpi = match tables() {
+		Some(acpi) => acpi,
+		None => {
+			println!("ACPI: not available");
+			return;
+		}
+	};
+	println!("ACPI: revision {} ({} tables)", acpi.rsdp.revision, acpi.tables.len());
+	for header in &acpi.tables {
+		println!("  {} at {:#x} ({} bytes, revision {})",
+			header.signature(), header.physical_address, header.length, header.revision);
+	}
+	if let Some(ref madt) = acpi.madt {
+		let enabled = madt.processors.iter().filter(|p| p.enabled).count();
+		println!("  {} processors ({} enabled), {} I/O APICs, {} interrupt overrides",
+			madt.processors.len(), enabled, madt.io_apics.len(), madt.interrupt_overrides.len());
+	}
+}
@@ -0,0 +1,190 @@
+use core::slice;
+use acpi::sdt::{checksum_valid, read_u8, read_u16, read_u32, read_u64};
+use memory::{phys_to_virt, PhysicalAddress};
+
+//Root System Description Pointer - points at the RSDT (ACPI 1.0) or the XSDT (ACPI 2.0+)
+// http://wiki.osdev.org/RSDP
+
+const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+//Multiboot2 hands over a copy of the RSDP in one of these tags
+const MULTIBOOT_TAG_END: u32 = 0;
+const MULTIBOOT_TAG_ACPI_OLD: u32 = 14;
+const MULTIBOOT_TAG_ACPI_NEW: u32 = 15;
+
+//Without the tag the RSDP sits on a 16 byte boundary in the first KiB of the EBDA or in the BIOS area
+const EBDA_SEGMENT_POINTER: PhysicalAddress = 0x40E;
+const BIOS_AREA_START: PhysicalAddress = 0xE0000;
+const BIOS_AREA_END: PhysicalAddress = 0x100000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rsdp {
+	pub revision: u8,
+	pub oem_id: [u8; 6],
+	pub rsdt_address: u32,
+	//only valid from revision 2 on
+	pub xsdt_address: Option<u64>
+}
+
+impl Rsdp {
+	//Checks the signature and checksums of a candidate
+	fn parse(bytes: &[u8]) -> Option<Rsdp> {
+		if bytes.len() < RSDP_V1_SIZE || &bytes[0..8] != RSDP_SIGNATURE || !checksum_valid(&bytes[0..RSDP_V1_SIZE]) {
+			return None;
+		}
+		let revision = read_u8(bytes, 15);
+		let mut xsdt_address = None;
+		if revision >= 2 {
+			if bytes.len() < RSDP_V2_SIZE {
+				return None;
+			}
+			let length = read_u32(bytes, 20) as usize;
+			if length < RSDP_V2_SIZE || length > bytes.len() || !checksum_valid(&bytes[0..length]) {
+				return None;
+			}
+			xsdt_address = Some(read_u64(bytes, 24));
+		}
+		let mut oem_id = [0; 6];
+		oem_id.copy_from_slice(&bytes[9..15]);
+		Some(Rsdp {
+			revision: revision,
+			oem_id: oem_id,
+			rsdt_address: read_u32(bytes, 16),
+			xsdt_address: xsdt_address
+		})
+	}
+}
+
+//Prefers the ACPI 2.0 tag from the boot loader, then the ACPI 1.0 tag, then scans the EBDA and BIOS area
+pub fn find_rsdp(multiboot_information_address: PhysicalAddress) -> Option<Rsdp> {
+	let tagged = unsafe {
+		multiboot_tag(multiboot_information_address, MULTIBOOT_TAG_ACPI_NEW)
+			.or_else(|| multiboot_tag(multiboot_information_address, MULTIBOOT_TAG_ACPI_OLD))
+	};
+	tagged.and_then(Rsdp::parse).or_else(|| unsafe {
+		let ebda = (read_u16(physical_bytes(EBDA_SEGMENT_POINTER, 2), 0) as PhysicalAddress) << 4;
+		scan(ebda, ebda + 1024).or_else(|| scan(BIOS_AREA_START, BIOS_AREA_END))
+	})
+}
+
+unsafe fn physical_bytes(address: PhysicalAddress, length: usize) -> &'static [u8] {
+	slice::from_raw_parts(phys_to_virt(address) as *const u8, length)
+}
+
+//Returns the body of the first multiboot tag of the given type
+unsafe fn multiboot_tag(multiboot_information_address: PhysicalAddress, tag_type: u32) -> Option<&'static [u8]> {
+	let total_size = read_u32(physical_bytes(multiboot_information_address, 4), 0) as usize;
+	find_tag(physical_bytes(multiboot_information_address, total_size), tag_type)
+}
+
+//Walks the tags of the multiboot information in info. A tag that claims to run past the end ends the walk.
+fn find_tag(info: &[u8], tag_type: u32) -> Option<&[u8]> {
+	let mut offset = 8;
+	while offset + 8 <= info.len() {
+		let current_type = read_u32(info, offset);
+		let size = read_u32(info, offset + 4) as usize;
+		if current_type == MULTIBOOT_TAG_END || size < 8 || offset + size > info.len() {
+			break;
+		}
+		if current_type == tag_type {
+			return Some(&info[offset + 8..offset + size]);
+		}
+		offset = (offset + size + 7) & !7;
+	}
+	None
+}
+
+unsafe fn scan(start: PhysicalAddress, end: PhysicalAddress) -> Option<Rsdp> {
+	let area = physical_bytes(start, end - start);
+	(0..area.len()).step_by(16)
+		.filter_map(|offset| Rsdp::parse(&area[offset..]))
+		.next()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	static RSDP_V2: [u8; 36] = [
+		b'R', b'S', b'D', b' ', b'P', b'T', b'R', b' ',
+		0x05,//checksum of the first 20 bytes
+		b'B', b'O', b'C', b'H', b'S', b' ',
+		2,//revision
+		0x34, 0x12, 0xfe, 0x07,//RSDT address
+		36, 0, 0, 0,//length
+		0x00, 0x13, 0xfe, 0x07, 0x00, 0x00, 0x00, 0x00,//XSDT address
+		0xc4,//checksum of all 36 bytes
+		0, 0, 0,
+	];
+	static RSDP_V1: [u8; 20] = [
+		b'R', b'S', b'D', b' ', b'P', b'T', b'R', b' ',
+		0x07,//checksum
+		b'B', b'O', b'C', b'H', b'S', b' ',
+		0,//revision
+		0x34, 0x12, 0xfe, 0x07,//RSDT address
+	];
+
+	#[test]
+	fn parses_acpi_2_rsdp() {
+		let rsdp = Rsdp::parse(&RSDP_V2).unwrap();
+		assert_eq!(rsdp.revision, 2);
+		assert_eq!(&rsdp.oem_id, b"BOCHS ");
+		assert_eq!(rsdp.rsdt_address, 0x7fe_1234);
+		assert_eq!(rsdp.xsdt_address, Some(0x7fe_1300));
+	}
+
+	#[test]
+	fn parses_acpi_1_rsdp() {
+		let rsdp = Rsdp::parse(&RSDP_V1).unwrap();
+		assert_eq!(rsdp.revision, 0);
+		assert_eq!(rsdp.rsdt_address, 0x7fe_1234);
+		assert_eq!(rsdp.xsdt_address, None);
+	}
+
+	#[test]
+	fn rejects_bad_rsdp() {
+		let mut bytes = RSDP_V2.to_vec();
+		//breaks the extended checksum only
+		bytes[30] ^= 1;
+		assert!(Rsdp::parse(&bytes).is_none());
+		assert!(Rsdp::parse(&RSDP_V2[0..RSDP_V1_SIZE]).is_none());
+		assert!(Rsdp::parse(&RSDP_V1[0..RSDP_V1_SIZE - 1]).is_none());
+		bytes = RSDP_V1.to_vec();
+		bytes[0] = b'X';
+		assert!(Rsdp::parse(&bytes).is_none());
+	}
+
+	//Multiboot information with a total size and reserved field followed by the given tags
+	fn multiboot_info(tags: &[(u32, u32, &[u8])]) -> Vec<u8> {
+		let mut info = vec![0; 8];
+		for &(tag_type, size, body) in tags {
+			info.extend_from_slice(&[tag_type as u8, (tag_type >> 8) as u8, 0, 0]);
+			info.extend_from_slice(&[size as u8, (size >> 8) as u8, 0, 0]);
+			info.extend_from_slice(body);
+			while info.len() % 8 != 0 {
+				info.push(0);
+			}
+		}
+		let total_size = info.len();
+		info[0] = total_size as u8;
+		info[1] = (total_size >> 8) as u8;
+		info
+	}
+
+	#[test]
+	fn finds_multiboot_tag() {
+		let info = multiboot_info(&[(1, 12, b"abcd"), (MULTIBOOT_TAG_ACPI_NEW, 8 + 36, &RSDP_V2[..]), (MULTIBOOT_TAG_END, 8, b"")]);
+		assert_eq!(find_tag(&info, MULTIBOOT_TAG_ACPI_NEW), Some(&RSDP_V2[..]));
+		assert_eq!(find_tag(&info, MULTIBOOT_TAG_ACPI_OLD), None);
+	}
+
+	#[test]
+	fn tag_running_past_the_end_is_ignored() {
+		let mut info = multiboot_info(&[(MULTIBOOT_TAG_ACPI_NEW, 8 + 36, &RSDP_V2[..])]);
+		//claims to be bigger than the information
+		info[12] = 200;
+		assert_eq!(find_tag(&info, MULTIBOOT_TAG_ACPI_NEW), None);
+	}
+}
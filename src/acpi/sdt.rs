@@ -0,0 +1,192 @@
+use core::{slice, str};
+use memory::{map_physical_region, unmap_physical_region, EntryFlags, PhysicalAddress, PAGE_SIZE};
+
+//Every ACPI table except the RSDP starts with this header
+// http://wiki.osdev.org/RSDT
+
+pub const SDT_HEADER_SIZE: usize = 36;
+
+//All bytes of a table (or of the RSDP) have to add up to zero
+pub fn checksum_valid(bytes: &[u8]) -> bool {
+	bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+//ACPI is little endian and its fields are not aligned - read them byte by byte
+pub fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+	bytes[offset]
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+	(bytes[offset + 1] as u16) << 8 | (bytes[offset] as u16)
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+	(read_u16(bytes, offset + 2) as u32) << 16 | (read_u16(bytes, offset) as u32)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+	(read_u32(bytes, offset + 4) as u64) << 32 | (read_u32(bytes, offset) as u64)
+}
+
+//Register location used by the FADT and HPET tables
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+	pub address_space: u8, // 0 = system memory, 1 = system I/O
+	pub bit_width: u8,
+	pub bit_offset: u8,
+	pub access_size: u8,
+	pub address: u64
+}
+
+pub const GENERIC_ADDRESS_SIZE: usize = 12;
+
+impl GenericAddress {
+	pub fn read(bytes: &[u8], offset: usize) -> GenericAddress {
+		GenericAddress {
+			address_space: read_u8(bytes, offset),
+			bit_width: read_u8(bytes, offset + 1),
+			bit_offset: read_u8(bytes, offset + 2),
+			access_size: read_u8(bytes, offset + 3),
+			address: read_u64(bytes, offset + 4)
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SdtHeader {
+	pub signature: [u8; 4],
+	pub length: u32,
+	pub revision: u8,
+	pub oem_id: [u8; 6],
+	pub oem_table_id: [u8; 8],
+	pub oem_revision: u32,
+	pub physical_address: PhysicalAddress
+}
+
+impl SdtHeader {
+	pub fn signature(&self) -> &str {
+		str::from_utf8(&self.signature).unwrap_or("????")
+	}
+}
+
+//A mapped table whose checksum has been checked
+pub struct Sdt {
+	pub header: SdtHeader,
+	bytes: &'static [u8]
+}
+
+impl Sdt {
+	//Maps the table at address. The mapping is never removed - the tables are only parsed once at boot.
+	pub unsafe fn load(address: PhysicalAddress) -> Result<Sdt, &'static str> {
+		//the header mapping covers whole pages, which is often enough for all of the table
+		let mapped_size = ((address + SDT_HEADER_SIZE + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) - address;
+		let mut virt = map_physical_region(address, mapped_size, EntryFlags::empty());
+		let length = read_u32(slice::from_raw_parts(virt as *const u8, SDT_HEADER_SIZE), 4) as usize;
+		if length < SDT_HEADER_SIZE {
+			unmap_physical_region(virt, mapped_size);
+			return Err("ACPI table is shorter than its header");
+		}
+		if length > mapped_size {
+			unmap_physical_region(virt, mapped_size);
+			virt = map_physical_region(address, length, EntryFlags::empty());
+		}
+
+		let table = Sdt::from_bytes(slice::from_raw_parts(virt as *const u8, length), address);
+		if table.is_err() {
+			unmap_physical_region(virt, if length > mapped_size { length } else { mapped_size });
+		}
+		table
+	}
+
+	//Checks the length and checksum of a table that starts at bytes[0] and was found at address
+	pub fn from_bytes(bytes: &'static [u8], address: PhysicalAddress) -> Result<Sdt, &'static str> {
+		if bytes.len() < SDT_HEADER_SIZE {
+			return Err("ACPI table is shorter than its header");
+		}
+		let length = read_u32(bytes, 4) as usize;
+		if length < SDT_HEADER_SIZE || length > bytes.len() {
+			return Err("ACPI table length is out of range");
+		}
+		let bytes = &bytes[0..length];
+		if !checksum_valid(bytes) {
+			return Err("ACPI table checksum mismatch");
+		}
+
+		let mut header = SdtHeader {
+			signature: [0; 4],
+			length: length as u32,
+			revision: read_u8(bytes, 8),
+			oem_id: [0; 6],
+			oem_table_id: [0; 8],
+			oem_revision: read_u32(bytes, 24),
+			physical_address: address
+		};
+		header.signature.copy_from_slice(&bytes[0..4]);
+		header.oem_id.copy_from_slice(&bytes[10..16]);
+		header.oem_table_id.copy_from_slice(&bytes[16..24]);
+		Ok(Sdt { header: header, bytes: bytes })
+	}
+
+	//The whole table including the header, so offsets match the specification
+	pub fn bytes(&self) -> &'static [u8] {
+		self.bytes
+	}
+
+	pub fn len(&self) -> usize {
+		self.bytes.len()
+	}
+}
+
+//A table with the given signature and revision whose contents after the header are body. The length
+//and checksum are filled in, so the parsers' tests only spell out the bytes they check.
+#[cfg(test)]
+pub fn test_table_bytes(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+	let length = SDT_HEADER_SIZE + body.len();
+	let mut bytes = Vec::with_capacity(length);
+	bytes.extend_from_slice(signature);
+	bytes.extend_from_slice(&[length as u8, (length >> 8) as u8, (length >> 16) as u8, (length >> 24) as u8]);
+	bytes.extend_from_slice(&[revision, 0]);
+	bytes.extend_from_slice(b"BOCHS BXPC    ");
+	bytes.extend_from_slice(&[1, 0, 0, 0]);//OEM revision
+	bytes.extend_from_slice(b"BXPC");
+	bytes.extend_from_slice(&[1, 0, 0, 0]);//creator revision
+	bytes.extend_from_slice(body);
+	bytes[9] = 0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+	bytes
+}
+
+#[cfg(test)]
+pub fn test_table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Sdt {
+	let bytes = test_table_bytes(signature, revision, body);
+	Sdt::from_bytes(Box::leak(bytes.into_boxed_slice()), 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_header() {
+		let bytes = test_table_bytes(b"APIC", 3, &[1, 2, 3, 4]);
+		let table = Sdt::from_bytes(Box::leak(bytes.into_boxed_slice()), 0x7fe_1500).unwrap();
+		assert_eq!(table.header.signature(), "APIC");
+		assert_eq!(table.header.length, 40);
+		assert_eq!(table.header.revision, 3);
+		assert_eq!(&table.header.oem_id, b"BOCHS ");
+		assert_eq!(&table.header.oem_table_id, b"BXPC    ");
+		assert_eq!(table.header.oem_revision, 1);
+		assert_eq!(table.header.physical_address, 0x7fe_1500);
+		assert_eq!(&table.bytes()[SDT_HEADER_SIZE..], &[1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn rejects_broken_tables() {
+		let bytes: &'static [u8] = Box::leak(test_table_bytes(b"APIC", 1, &[1, 2, 3, 4]).into_boxed_slice());
+		let mut corrupted = bytes.to_vec();
+		corrupted[SDT_HEADER_SIZE] ^= 1;
+		assert!(Sdt::from_bytes(Box::leak(corrupted.into_boxed_slice()), 0).is_err());
+		assert!(Sdt::from_bytes(&bytes[0..bytes.len() - 1], 0).is_err());
+		assert!(Sdt::from_bytes(&bytes[0..SDT_HEADER_SIZE - 1], 0).is_err());
+		assert!(Sdt::from_bytes(bytes, 0).is_ok());
+	}
+}
@@ -1,21 +1,28 @@
-global idtr
-global setup_idt
+global interrupt_stubs
 
-extern gdt64.code
-
-section .interrupts
+section .interrupts progbits alloc exec nowrite align=16
 bits 64
 
-; Define IDT code for 255 interrupt handlers - putting interrupt code into .int_code
-interrupts:
-.first:
-	push qword 0
-	jmp qword .handle
-.second:
-%assign i 1
-%rep 255
-	push qword i
-	jmp qword .handle
+; Every stub is padded to the same size so idt.rs can find stub i at interrupt_stubs + SIZE_OF_INTCODE * i
+%define SIZE_OF_INTCODE 16
+
+; The CPU pushes an error code for these vectors only. Every other stub pushes a dummy one
+; so .handle always sees the vector number, the error code and then the interrupt frame.
+%macro interrupt_stub 1
+align SIZE_OF_INTCODE
+%if %1 == 8 || (%1 >= 10 && %1 <= 14) || %1 == 17 || %1 == 21 || %1 == 29 || %1 == 30
+%else
+	push qword 0 ; dummy error code
+%endif
+	push qword %1
+	jmp .handle
+%endmacro
+
+; Define IDT code for 256 interrupt handlers - putting interrupt code into .interrupts
+interrupt_stubs:
+%assign i 0
+%rep 256
+	interrupt_stub i
 %assign i i+1
 %endrep
 
@@ -40,9 +47,14 @@ interrupts:
 	push rdi
 	mov rdi, rsp
 
+	; The CPU aligned rsp to 16 bytes before pushing its 5 qwords, 18 more were pushed since, and
+	; the SysV ABI wants it aligned again at the call
+	sub rsp, 8
+
 	extern fault_handler
 	call fault_handler ; Call rust fault handler
 
+	add rsp, 8
 	pop rsp ; Pop stack pointer
 
 	pop rax ; Restore all registers
@@ -60,36 +72,5 @@ interrupts:
 	pop r14
 	pop r15
 	pop rbp
-	add rsp, 8 ; pop error code qword
+	add rsp, 16 ; pop the vector number and error code
 	iretq
-
-; IDTR definition
-idtr:
-	dw (idt.end - idt) + 1 ; idt limit - maximum addressable size in table
-	dq idt ; pointer to idt
-
-%define BASE_OF_SECTION 0x104000 ;IF INTERRUPTS EXPLODE - it is because the linker has relocated the interrupts!
-%define SIZE_OF_INTCODE (interrupts.second-interrupts.first)
-
-sectionbase:
-	dq BASE_OF_SECTION
-
-; IDT definition starts here
-idt:
-%assign i 0
-
-; create 255 IDT entries
-%rep 255
-	; interrupt handler code is located at interrupts + sizeof(interrupt code) * i
-	; we are in assembly so this should be located in the first segment so the middle and upper fields can be 0
-	dw ((BASE_OF_SECTION + (SIZE_OF_INTCODE*i)) & 0xFFFF) ;offsetl
-	dw gdt64.code ; pointer to GDT code segment - selector
-	db 0 ; zero
-	db (1<<7) | 0xE ; PRESENT | INTERRUPT64 - type and attributes
-	dw ((BASE_OF_SECTION + (SIZE_OF_INTCODE*i)) >> 16) ; offset middle bits
-	dd 0 ; offset higher bits
-	dd 0 ; always 0
-%assign i i+1
-%endrep
-.end:
-;end of IDT
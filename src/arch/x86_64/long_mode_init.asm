@@ -1,13 +1,20 @@
-global long_mode_start
-
-section .text
-bits 64
-long_mode_start:
-	; call the rust main
-	extern rust_main
-	call rust_main
-
-	; print `OK` to screen
-	mov rax, 0x2f592f412f4b2f4f
-    mov qword [0xb8000], rax
-    hlt
+global long_mode_start
+
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .text
+bits 64
+long_mode_start:
+	; we are now running in the higher half - move the stack up here too
+	mov rax, KERNEL_OFFSET
+	add rsp, rax
+
+	; call the rust main - rdi still holds the physical multiboot info address
+	extern rust_main
+	call rust_main
+
+	; print `OK` to screen
+	mov rax, 0x2f592f412f4b2f4f
+    mov rbx, KERNEL_OFFSET + 0xb8000
+    mov qword [rbx], rax
+    hlt
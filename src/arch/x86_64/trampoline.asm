@@ -0,0 +1,86 @@
+global trampoline_start
+global trampoline_end
+global trampoline_page_table
+global trampoline_stack
+global trampoline_entry
+global trampoline_cpu
+global trampoline_efer
+
+; Application processors start here in real mode, at the page named by the startup IPI. The code
+; is copied to TRAMPOLINE_ADDRESS (smp.rs) at runtime, so addresses are taken relative to it.
+TRAMPOLINE_ADDRESS equ 0x8000
+%define ADDR(label) (TRAMPOLINE_ADDRESS + (label - trampoline_start))
+
+; Only ever read from as the source of the copy
+section .rodata
+bits 16
+trampoline_start:
+	cli
+	cld
+	xor ax, ax
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+
+	o32 lgdt [ADDR(trampoline_gdt.pointer)]
+
+	; enable PAE and load the kernel's P4 - smp.rs makes sure it is below 4 GiB
+	mov eax, cr4
+	or eax, 1 << 5
+	mov cr4, eax
+	mov eax, [ADDR(trampoline_page_table)]
+	mov cr3, eax
+
+	; set the long mode bit, plus the no-execute bit if smp.rs found the CPU supports it
+	mov ecx, 0xC0000080
+	rdmsr
+	or eax, 1 << 8
+	or eax, [ADDR(trampoline_efer)]
+	wrmsr
+
+	; enabling protection and paging together goes straight from real mode to long mode
+	mov eax, cr0
+	or eax, (1 << 31) | (1 << 0)
+	mov cr0, eax
+
+	jmp trampoline_gdt.code:ADDR(trampoline_long_mode)
+
+bits 64
+trampoline_long_mode:
+	mov ax, trampoline_gdt.data
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	xor ax, ax
+	mov fs, ax
+	mov gs, ax
+
+	; the trampoline page is identity mapped until every AP is running - leave it right away
+	mov rsp, [ADDR(trampoline_stack)]
+	mov rdi, [ADDR(trampoline_cpu)]
+	mov rax, [ADDR(trampoline_entry)]
+	call rax
+.halt:
+	hlt
+	jmp .halt
+
+; Same layout as gdt64 in boot.asm, so the kernel selectors are valid as soon as we are in long mode
+align 8
+trampoline_gdt:
+	dq 0 ; zero entry
+.code: equ $ - trampoline_gdt
+	dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) ; code segment
+.data: equ $ - trampoline_gdt
+	dq (1<<44) | (1<<47) | (1<<41) ; data segment
+.pointer:
+	dw $ - trampoline_gdt - 1
+	dd ADDR(trampoline_gdt)
+
+; Filled in by smp.rs in the copy before each AP is started
+align 8
+trampoline_page_table: dq 0
+trampoline_stack: dq 0
+trampoline_entry: dq 0
+trampoline_cpu: dq 0
+trampoline_efer: dq 0
+trampoline_end:
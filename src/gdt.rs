@@ -0,0 +1,128 @@
+use core::mem::size_of;
+use smp::MAX_CPUS;
+
+//Replaces the boot GDT from boot.asm with one that also holds a Task State Segment, which is
+//what gives the CPU a known good stack to switch to when the kernel stack has overflowed
+// http://wiki.osdev.org/Task_State_Segment
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const TSS_SELECTOR: u16 = 0x18;
+
+//Interrupt stack table entry used by the double fault handler (entries are numbered from 1)
+pub const DOUBLE_FAULT_IST_INDEX: usize = 1;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+	reserved_1: u32,
+	pub privilege_stacks: [u64; 3],
+	reserved_2: u64,
+	pub interrupt_stacks: [u64; 7],
+	reserved_3: u64,
+	reserved_4: u16,
+	pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+	pub const fn new() -> TaskStateSegment {
+		TaskStateSegment {
+			reserved_1: 0,
+			privilege_stacks: [0; 3],
+			reserved_2: 0,
+			interrupt_stacks: [0; 7],
+			reserved_3: 0,
+			reserved_4: 0,
+			iomap_base: size_of::<TaskStateSegment>() as u16,
+		}
+	}
+}
+
+#[repr(C, packed)]
+struct GdtPointer {
+	limit: u16,
+	base: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct Gdt {
+	entries: [u64; 5],
+}
+
+const DESC_PRESENT: u64 = 1 << 47;
+const DESC_USER_SEGMENT: u64 = 1 << 44;
+const DESC_EXECUTABLE: u64 = 1 << 43;
+const DESC_READ_WRITE: u64 = 1 << 41;
+const DESC_LONG_MODE: u64 = 1 << 53;
+const DESC_TSS_AVAILABLE: u64 = 0x9 << 40;
+
+impl Gdt {
+	//null, kernel code and kernel data descriptors match gdt64 in boot.asm so the selectors stay valid
+	pub const fn new() -> Gdt {
+		Gdt {
+			entries: [
+				0,
+				DESC_USER_SEGMENT | DESC_PRESENT | DESC_READ_WRITE | DESC_EXECUTABLE | DESC_LONG_MODE,
+				DESC_USER_SEGMENT | DESC_PRESENT | DESC_READ_WRITE,
+				0,
+				0,
+			],
+		}
+	}
+
+	//A TSS descriptor takes up two GDT entries as it holds a full 64 bit base address
+	pub fn set_tss(&mut self, tss: &'static TaskStateSegment) {
+		let base = tss as *const _ as u64;
+		let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+
+		let low = (limit & 0xFFFF) |
+			((base & 0xFFFFFF) << 16) |
+			DESC_TSS_AVAILABLE |
+			DESC_PRESENT |
+			(((limit >> 16) & 0xF) << 48) |
+			(((base >> 24) & 0xFF) << 56);
+		let index = (TSS_SELECTOR / 8) as usize;
+		self.entries[index] = low;
+		self.entries[index + 1] = base >> 32;
+	}
+
+	//Loads the GDT and the task register - the GDT must live forever as the CPU keeps using it
+	pub unsafe fn load(&'static self) {
+		let pointer = GdtPointer {
+			limit: (size_of::<Gdt>() - 1) as u16,
+			base: self as *const _ as u64,
+		};
+		asm!("lgdt ($0)" :: "r" (&pointer) : "memory");
+		asm!("ltr $0" :: "r" (TSS_SELECTOR) : "memory");
+	}
+}
+
+//Base address of the GDT the CPU is using
+pub fn loaded_gdt_base() -> u64 {
+	let mut pointer = GdtPointer { limit: 0, base: 0 };
+	unsafe { asm!("sgdt ($0)" :: "r" (&mut pointer) : "memory"); }
+	pointer.base
+}
+
+//Every CPU needs a TSS of its own (loading one marks its descriptor busy), so each gets its own GDT too
+static mut GDTS: [Gdt; MAX_CPUS] = [Gdt::new(); MAX_CPUS];
+static mut TSSES: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::new(); MAX_CPUS];
+//The boot CPU's double fault stack - the other CPUs get theirs from memory::alloc_stack
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+pub fn init_gdt() {
+	let stack_top = unsafe { &DOUBLE_FAULT_STACK as *const _ as u64 + DOUBLE_FAULT_STACK_SIZE as u64 };
+	init_cpu_gdt(0, stack_top);
+}
+
+//Loads the GDT and TSS of the CPU this runs on - cpu is its index, not its APIC id
+pub fn init_cpu_gdt(cpu: usize, double_fault_stack_top: u64) {
+	assert!(cpu < MAX_CPUS, "no GDT for this CPU");
+	unsafe {
+		TSSES[cpu].interrupt_stacks[DOUBLE_FAULT_IST_INDEX - 1] = double_fault_stack_top;
+		GDTS[cpu].set_tss(&TSSES[cpu]);
+		GDTS[cpu].load();
+	}
+}
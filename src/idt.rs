@@ -0,0 +1,131 @@
+use core::mem::size_of;
+use gdt::{KERNEL_CODE_SELECTOR, DOUBLE_FAULT_IST_INDEX};
+
+//The Interrupt Descriptor Table - every vector points at its stub in interrupts.asm, which saves
+//the registers and calls fault_handler
+// http://wiki.osdev.org/Interrupt_Descriptor_Table
+
+pub const IDT_ENTRIES: usize = 256;
+
+//Every stub in interrupts.asm is padded to this size (SIZE_OF_INTCODE)
+const STUB_SIZE: usize = 16;
+
+#[derive(Clone, Copy)]
+pub enum GateType {
+	//interrupts are disabled while the handler runs
+	Interrupt = 0xE,
+	//interrupts stay enabled
+	Trap = 0xF,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct IdtEntry {
+	offset_low: u16,
+	selector: u16,
+	ist: u8,
+	type_attributes: u8,
+	offset_middle: u16,
+	offset_high: u32,
+	reserved: u32,
+}
+
+const ENTRY_PRESENT: u8 = 1 << 7;
+
+impl IdtEntry {
+	pub const fn missing() -> IdtEntry {
+		IdtEntry {
+			offset_low: 0,
+			selector: 0,
+			ist: 0,
+			type_attributes: GateType::Interrupt as u8,
+			offset_middle: 0,
+			offset_high: 0,
+			reserved: 0,
+		}
+	}
+
+	//Points the entry at handler in the kernel code segment and marks it present
+	pub fn set_handler(&mut self, handler: u64) {
+		self.offset_low = handler as u16;
+		self.offset_middle = (handler >> 16) as u16;
+		self.offset_high = (handler >> 32) as u32;
+		self.selector = KERNEL_CODE_SELECTOR;
+		self.set_present(true);
+	}
+
+	pub fn set_present(&mut self, present: bool) {
+		if present {
+			self.type_attributes |= ENTRY_PRESENT;
+		} else {
+			self.type_attributes &= !ENTRY_PRESENT;
+		}
+	}
+
+	pub fn set_gate_type(&mut self, gate_type: GateType) {
+		self.type_attributes = (self.type_attributes & !0xF) | gate_type as u8;
+	}
+
+	//Lowest privilege level allowed to raise this vector with the int instruction
+	pub fn set_privilege_level(&mut self, dpl: u8) {
+		assert!(dpl < 4, "privilege level must be 0 to 3");
+		self.type_attributes = (self.type_attributes & !(3 << 5)) | (dpl << 5);
+	}
+
+	//Interrupt stack table entry (1 to 7) to switch to, or 0 to stay on the current stack
+	pub fn set_stack_index(&mut self, index: usize) {
+		assert!(index < 8, "interrupt stack table index must be 0 to 7");
+		self.ist = index as u8;
+	}
+}
+
+#[repr(C, packed)]
+struct IdtPointer {
+	limit: u16,
+	base: u64,
+}
+
+pub struct Idt {
+	pub entries: [IdtEntry; IDT_ENTRIES],
+}
+
+impl Idt {
+	pub const fn new() -> Idt {
+		Idt {
+			entries: [IdtEntry::missing(); IDT_ENTRIES],
+		}
+	}
+
+	//The IDT must live forever as the CPU keeps using it
+	pub unsafe fn load(&'static self) {
+		let pointer = IdtPointer {
+			limit: (size_of::<Idt>() - 1) as u16,
+			base: self as *const _ as u64,
+		};
+		asm!("lidt ($0)" :: "r" (&pointer) : "memory");
+	}
+}
+
+extern {
+	static interrupt_stubs: u8;
+}
+
+static mut IDT: Idt = Idt::new();
+
+//Points every vector at its stub and loads the table. The double fault entry uses its own stack
+//from the TSS, which init_gdt sets up.
+pub fn init_idt() {
+	unsafe {
+		let stubs = &interrupt_stubs as *const u8 as u64;
+		for (vector, entry) in IDT.entries.iter_mut().enumerate() {
+			entry.set_handler(stubs + (vector * STUB_SIZE) as u64);
+		}
+		IDT.entries[8].set_stack_index(DOUBLE_FAULT_IST_INDEX);
+		IDT.load();
+	}
+}
+
+//Every CPU shares the one IDT - the others only have to load it once init_idt has filled it in
+pub fn load_idt() {
+	unsafe { IDT.load(); }
+}
@@ -0,0 +1,182 @@
+use idt;
+use io;
+use memory;
+use percpu;
+use sync::IrqMutex;
+use x86;
+
+//Every vector enters fault_handler through the stubs in interrupts.asm. Exceptions (0-31) and the
+//IRQs (0x20-0x2F) are dispatched to handlers registered at runtime.
+
+pub const EXCEPTION_COUNT: usize = 32;
+//IRQ 0 arrives at this vector and the others follow in order, whether they come from the PICs or
+//the I/O APIC (see io::pic and io::apic)
+pub const IRQ_BASE: usize = 0x20;
+pub const IRQ_COUNT: usize = 16;
+
+//The registers saved by interrupts.asm, followed by the frame the CPU pushed
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(packed)]
+pub struct Regs {
+	pub sp: usize,
+	pub ax: usize,
+	pub bx: usize,
+	pub cx: usize,
+	pub dx: usize,
+	pub di: usize,
+	pub si: usize,
+	pub r8: usize,
+	pub r9: usize,
+	pub r10: usize,
+	pub r11: usize,
+	pub r12: usize,
+	pub r13: usize,
+	pub r14: usize,
+	pub r15: usize,
+	pub bp: usize,
+	pub interrupt: usize,
+	pub error_code: usize, // zero for vectors the CPU doesn't push one for
+	pub ip: usize,
+	pub cs: usize,
+	pub flags: usize
+}
+
+pub type ExceptionHandler = fn(&Regs);
+pub type IrqHandler = fn(&Regs);
+
+//Only changed with interrupts disabled so a handler is never seen half written
+static mut EXCEPTION_HANDLERS: [Option<ExceptionHandler>; EXCEPTION_COUNT] = [None; EXCEPTION_COUNT];
+//Registered from normal code on any CPU and read by fault_handler
+static IRQ_HANDLERS: IrqMutex<[Option<IrqHandler>; IRQ_COUNT]> = IrqMutex::new([None; IRQ_COUNT]);
+static UNHANDLED_IRQS: IrqMutex<[u64; IRQ_COUNT]> = IrqMutex::new([0; IRQ_COUNT]);
+
+const EXCEPTION_NAMES: [&'static str; EXCEPTION_COUNT] = [
+	"Divide by zero exception",
+	"Debug exception",
+	"Non-maskable interrupt",
+	"Breakpoint exception",
+	"Overflow exception",
+	"Bound range exceeded exception",
+	"Invalid opcode exception",
+	"Device not available exception",
+	"Double fault",
+	"Coprocessor Segment Overrun", // legacy
+	"Invalid TSS exception",
+	"Segment not present exception",
+	"Stack-segment fault",
+	"General protection fault",
+	"Page fault",
+	"Reserved exception",
+	"x87 floating-point exception",
+	"Alignment check exception",
+	"Machine check exception",
+	"SIMD floating-point exception",
+	"Virtualization exception",
+	"Control protection exception",
+	"Reserved exception",
+	"Reserved exception",
+	"Reserved exception",
+	"Reserved exception",
+	"Reserved exception",
+	"Reserved exception",
+	"Hypervisor injection exception",
+	"VMM communication exception",
+	"Security exception",
+	"Reserved exception",
+];
+
+//Loads the IDT and installs the kernel's own exception handlers
+pub fn init_interrupts() {
+	idt::init_idt();
+	register_exception_handler(0x8, double_fault);
+	register_exception_handler(0xE, page_fault);
+}
+
+//Sets the handler for an exception vector. Exceptions without a handler print the registers and halt.
+pub fn register_exception_handler(vector: usize, handler: ExceptionHandler) {
+	assert!(vector < EXCEPTION_COUNT, "not an exception vector");
+	x86::without_interrupts(|| unsafe { EXCEPTION_HANDLERS[vector] = Some(handler); });
+}
+
+//Sets the handler for an IRQ (0-15) and unmasks it. The interrupt controller is acknowledged after the handler returns.
+pub fn register_irq_handler(irq: usize, handler: IrqHandler) -> Result<(), &'static str> {
+	if irq >= IRQ_COUNT {
+		return Err("No such IRQ");
+	}
+	let mut handlers = IRQ_HANDLERS.lock();
+	if handlers[irq].is_some() {
+		return Err("IRQ already has a handler");
+	}
+	handlers[irq] = Some(handler);
+	unsafe { io::set_irq_masked(irq, false); }
+	Ok(())
+}
+
+//Masks the IRQ again and removes its handler
+pub fn unregister_irq_handler(irq: usize) {
+	assert!(irq < IRQ_COUNT, "no such IRQ");
+	let mut handlers = IRQ_HANDLERS.lock();
+	unsafe { io::set_irq_masked(irq, true); }
+	handlers[irq] = None;
+}
+
+//How many times the IRQ fired without a handler to take it
+pub fn unhandled_irq_count(irq: usize) -> u64 {
+	assert!(irq < IRQ_COUNT, "no such IRQ");
+	UNHANDLED_IRQS.lock()[irq]
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern fn fault_handler(regs: &Regs) {
+	let vector = regs.interrupt;
+	let this_cpu = percpu::current();
+	this_cpu.enter_interrupt();
+	if vector < EXCEPTION_COUNT {
+		match unsafe { EXCEPTION_HANDLERS[vector] } {
+			Some(handler) => handler(regs),
+			None => halt_with_registers(regs, EXCEPTION_NAMES[vector]),
+		}
+	} else if vector >= IRQ_BASE && vector < IRQ_BASE + IRQ_COUNT {
+		let irq = vector - IRQ_BASE;
+		if !unsafe { io::is_spurious_irq(irq) } {
+			//copied out so a handler can register others
+			let handler = IRQ_HANDLERS.lock()[irq];
+			match handler {
+				Some(handler) => handler(regs),
+				None => UNHANDLED_IRQS.lock()[irq] += 1,
+			}
+			unsafe { io::end_of_interrupt(irq); }
+		}
+	} else if vector == io::apic::SPURIOUS_VECTOR {
+		//the local APIC expects no EOI for it
+	} else {
+		println!("Unknown interrupt: {:X}", vector);
+	}
+	this_cpu.leave_interrupt();
+}
+
+pub fn halt_with_registers(regs: &Regs, name: &str) -> ! {
+	println!("  INT {:X}: {}    ERR: {:X}", regs.interrupt, name, regs.error_code);
+	println!("    CS:  {:08X}    IP:  {:08X}    FLG: {:08X}", regs.cs, regs.ip, regs.flags);
+	println!("    SP:  {:08X}    BP:  {:08X}", regs.sp, regs.bp);
+	println!("    AX:  {:08X}    BX:  {:08X}    CX:  {:08X}    DX:  {:08X}", regs.ax, regs.bx, regs.cx, regs.dx);
+	println!("    DI:  {:08X}    SI:  {:08X}", regs.di, regs.si);
+	println!("HALT");
+	loop { unsafe { asm!("hlt"); } }
+}
+
+fn double_fault(regs: &Regs) {
+	if memory::is_stack_guard_address(unsafe { x86::cr2() } as usize) {
+		halt_with_registers(regs, "Double fault: kernel stack overflow");
+	}
+	halt_with_registers(regs, "Double fault");
+}
+
+fn page_fault(regs: &Regs) {
+	let address = unsafe { x86::cr2() } as usize;
+	if !memory::handle_page_fault(address, regs.error_code as u64) {
+		memory::print_page_fault(address, regs.error_code as u64);
+		halt_with_registers(regs, "Page fault");
+	}
+}
@@ -0,0 +1,353 @@
+use core::ptr;
+use acpi::Madt;
+use io::port::{Io, Port};
+use interrupts::{IRQ_BASE, IRQ_COUNT};
+use memory::{map_physical_region, PhysicalAddress, VirtualAddress, WRITABLE, NO_CACHE, WRITE_THROUGH};
+use x86;
+use x86::cpuid;
+
+//Local APIC (one per CPU - timer, EOI, IPIs) and I/O APIC (routes the ISA and PCI IRQs)
+// http://wiki.osdev.org/APIC
+// http://wiki.osdev.org/IOAPIC
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+//Where the firmware puts the first I/O APIC unless the MADT says otherwise
+pub const DEFAULT_IO_APIC_ADDRESS: PhysicalAddress = 0xFEC0_0000;
+
+//Delivered by the local APIC when an interrupt goes away before it is accepted - must not be acknowledged
+pub const SPURIOUS_VECTOR: usize = 0xFF;
+
+//Local APIC registers (offsets from its base)
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const LAPIC_SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+//Interrupt command register - sends inter-processor interrupts
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+//Rate of the LAPIC timer, which takes over IRQ 0 from the PIT
+pub const TIMER_FREQUENCY: u32 = 100;
+
+//The PIT only calibrates the LAPIC timer - its channel 2 is polled through the speaker port
+const PIT_FREQUENCY: u32 = 1193182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+const PIT_GATE_CHANNEL2: u8 = 1 << 0;
+const PIT_SPEAKER: u8 = 1 << 1;
+const PIT_CHANNEL2_OUTPUT: u8 = 1 << 5;
+const CALIBRATION_MS: u32 = 10;
+//channel 2 counts down 16 bits at PIT_FREQUENCY
+const PIT_MAX_SLEEP_US: u32 = 54_000;
+
+//I/O APIC registers - selected through IOREGSEL and accessed through IOWIN
+const IOAPIC_IOREGSEL: usize = 0x00;
+const IOAPIC_IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+//Every CPU reaches its own local APIC through the same registers, so once init_apic has published it
+//the struct is shared read-only - timer_ticks_per_ms is only written before that
+pub struct LocalApic {
+	base: VirtualAddress,
+	timer_ticks_per_ms: u32
+}
+
+impl LocalApic {
+	unsafe fn read(&self, register: usize) -> u32 {
+		ptr::read_volatile((self.base + register) as *const u32)
+	}
+
+	unsafe fn write(&self, register: usize, value: u32) {
+		ptr::write_volatile((self.base + register) as *mut u32, value);
+	}
+
+	pub fn id(&self) -> u8 {
+		unsafe { (self.read(LAPIC_ID) >> 24) as u8 }
+	}
+
+	pub fn end_of_interrupt(&self) {
+		unsafe { self.write(LAPIC_EOI, 0); }
+	}
+
+	//Software enables the APIC and keeps the 8259s' virtual wire input on LINT0 masked
+	unsafe fn enable(&self) {
+		self.write(LAPIC_TASK_PRIORITY, 0);
+		self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+		self.write(LAPIC_LVT_ERROR, LVT_MASKED);
+		self.write(LAPIC_SPURIOUS, SPURIOUS_VECTOR as u32 | LAPIC_SPURIOUS_ENABLE);
+	}
+
+	//Counts how far the timer runs down while the PIT's channel 2 counts CALIBRATION_MS
+	unsafe fn calibrate_timer(&mut self) {
+		self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+		self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+		self.write(LAPIC_TIMER_INITIAL_COUNT, 0xFFFF_FFFF);
+		pit_sleep(CALIBRATION_MS * 1000);
+		let elapsed = 0xFFFF_FFFF - self.read(LAPIC_TIMER_CURRENT_COUNT);
+		self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+		self.timer_ticks_per_ms = elapsed / CALIBRATION_MS;
+	}
+
+	//Fires vector periodically at frequency Hz. The timer starts masked.
+	unsafe fn start_timer(&mut self, vector: usize, frequency: u32) {
+		let count = self.timer_ticks_per_ms * 1000 / frequency;
+		self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+		self.write(LAPIC_LVT_TIMER, vector as u32 | LVT_TIMER_PERIODIC | LVT_MASKED);
+		self.write(LAPIC_TIMER_INITIAL_COUNT, count);
+	}
+
+	pub fn set_timer_masked(&self, masked: bool) {
+		unsafe {
+			let lvt = self.read(LAPIC_LVT_TIMER);
+			self.write(LAPIC_LVT_TIMER, if masked { lvt | LVT_MASKED } else { lvt & !LVT_MASKED });
+		}
+	}
+
+	//Sends an IPI to the local APIC with the given ID and waits until it has been accepted
+	fn send_ipi(&self, apic_id: u8, command: u32) {
+		unsafe {
+			self.write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+			self.write(LAPIC_ICR_LOW, command);
+			while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+		}
+	}
+
+	//Resets the CPU into the wait-for-startup state
+	pub fn send_init(&self, apic_id: u8) {
+		self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+	}
+
+	//Starts a CPU waiting after an INIT in real mode at page * 4 KiB (below 1 MiB)
+	pub fn send_startup(&self, apic_id: u8, page: usize) {
+		assert!(page < 0x100, "startup page must be below 1 MiB");
+		self.send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+	}
+}
+
+//Busy waits on the PIT's channel 2, which nothing else uses once the APIC is in charge. Only one
+//CPU may use it at a time.
+pub fn pit_sleep(microseconds: u32) {
+	assert!(microseconds <= PIT_MAX_SLEEP_US, "PIT can't count that long");
+	let count = (PIT_FREQUENCY as u64 * microseconds as u64 / 1_000_000) as u16;
+	unsafe {
+		let mut gate: Port<u8> = Port::new(PIT_GATE_PORT);
+		let mut command: Port<u8> = Port::new(PIT_COMMAND);
+		let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+
+		let value = gate.read() & !PIT_SPEAKER;
+		gate.write(value & !PIT_GATE_CHANNEL2);
+		command.write(0b1011_0000);//channel 2, low then high byte, mode 0 (output goes high at zero)
+		channel2.write(count as u8);
+		channel2.write((count >> 8) as u8);
+
+		gate.write(value | PIT_GATE_CHANNEL2);//raising the gate starts the count
+		while gate.read() & PIT_CHANNEL2_OUTPUT == 0 {}
+		gate.write(value & !PIT_GATE_CHANNEL2);
+	}
+}
+
+pub struct IoApic {
+	base: VirtualAddress,
+	//Global system interrupt of the first input
+	gsi_base: u32,
+	redirection_entries: u32
+}
+
+impl IoApic {
+	unsafe fn read(&self, register: u32) -> u32 {
+		ptr::write_volatile((self.base + IOAPIC_IOREGSEL) as *mut u32, register);
+		ptr::read_volatile((self.base + IOAPIC_IOWIN) as *const u32)
+	}
+
+	unsafe fn write(&mut self, register: u32, value: u32) {
+		ptr::write_volatile((self.base + IOAPIC_IOREGSEL) as *mut u32, register);
+		ptr::write_volatile((self.base + IOAPIC_IOWIN) as *mut u32, value);
+	}
+
+	//Maps the registers and masks every input
+	pub unsafe fn new(address: PhysicalAddress, gsi_base: u32) -> IoApic {
+		let mut io_apic = IoApic {
+			base: map_physical_region(address, 0x20, WRITABLE | NO_CACHE | WRITE_THROUGH),
+			gsi_base: gsi_base,
+			redirection_entries: 0
+		};
+		io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+		for gsi in gsi_base..gsi_base + io_apic.redirection_entries {
+			io_apic.set_masked(gsi, true);
+		}
+		io_apic
+	}
+
+	pub fn handles(&self, gsi: u32) -> bool {
+		gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+	}
+
+	//Delivers gsi as vector to the local APIC with the given ID - the entry stays masked
+	pub unsafe fn route(&mut self, gsi: u32, vector: usize, apic_id: u8, active_low: bool, level_triggered: bool) {
+		let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+		let mut low = vector as u32 | REDIRECTION_MASKED;
+		if active_low {
+			low |= REDIRECTION_ACTIVE_LOW;
+		}
+		if level_triggered {
+			low |= REDIRECTION_LEVEL_TRIGGERED;
+		}
+		self.write(register + 1, (apic_id as u32) << 24);
+		self.write(register, low);
+	}
+
+	pub unsafe fn set_masked(&mut self, gsi: u32, masked: bool) {
+		let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+		let low = self.read(register);
+		self.write(register, if masked { low | REDIRECTION_MASKED } else { low & !REDIRECTION_MASKED });
+	}
+}
+
+//How an ISA IRQ reaches the I/O APIC. Without an override from the MADT IRQ n is GSI n, edge
+//triggered and active high.
+#[derive(Clone, Copy)]
+pub struct IrqRoute {
+	pub gsi: u32,
+	pub active_low: bool,
+	pub level_triggered: bool
+}
+
+impl IrqRoute {
+	const fn isa(irq: u32) -> IrqRoute {
+		IrqRoute { gsi: irq, active_low: false, level_triggered: false }
+	}
+}
+
+static mut LOCAL_APIC: Option<LocalApic> = None;
+pub static mut IO_APIC: Option<IoApic> = None;
+static mut IRQ_ROUTES: [IrqRoute; IRQ_COUNT] = [
+	IrqRoute::isa(0), IrqRoute::isa(1), IrqRoute::isa(2), IrqRoute::isa(3),
+	IrqRoute::isa(4), IrqRoute::isa(5), IrqRoute::isa(6), IrqRoute::isa(7),
+	IrqRoute::isa(8), IrqRoute::isa(9), IrqRoute::isa(10), IrqRoute::isa(11),
+	IrqRoute::isa(12), IrqRoute::isa(13), IrqRoute::isa(14), IrqRoute::isa(15),
+];
+
+//The IRQ the LAPIC timer stands in for - the PIT's input on the I/O APIC stays masked
+pub const TIMER_IRQ: usize = 0;
+//The 8259 cascade never fires - firmware usually reuses its GSI for the PIT through an override
+const CASCADE_IRQ: usize = 2;
+
+pub fn apic_supported() -> bool {
+	cpuid::has_feature(cpuid::APIC)
+}
+
+//Replaces an ISA IRQ's identity route - must be called before init_apic
+pub fn set_irq_route(irq: usize, route: IrqRoute) {
+	assert!(irq < IRQ_COUNT, "no such IRQ");
+	unsafe { IRQ_ROUTES[irq] = route; }
+}
+
+//Takes the ISA interrupt overrides from the MADT and returns the address and first GSI of the I/O
+//APIC the ISA IRQs are wired to
+pub fn configure_from_madt(madt: &Madt) -> Option<(PhysicalAddress, u32)> {
+	for interrupt_override in madt.interrupt_overrides.iter().filter(|o| o.bus == 0) {
+		if (interrupt_override.irq as usize) < IRQ_COUNT {
+			set_irq_route(interrupt_override.irq as usize, IrqRoute {
+				gsi: interrupt_override.gsi,
+				active_low: interrupt_override.active_low(),
+				level_triggered: interrupt_override.level_triggered()
+			});
+		}
+	}
+	madt.io_apic_for(0).map(|io_apic| (io_apic.address, io_apic.gsi_base))
+}
+
+//Enables this CPU's local APIC, routes the ISA IRQs through the I/O APIC at io_apic_address to
+//their usual vectors (IRQ_BASE + irq) and starts the LAPIC timer in place of IRQ 0. The 8259s must
+//already be disabled. Every IRQ starts masked.
+pub unsafe fn init_apic(io_apic_address: PhysicalAddress, gsi_base: u32) {
+	let apic_base = x86::rdmsr(IA32_APIC_BASE);
+	x86::wrmsr(IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+	let physical = (apic_base & APIC_BASE_ADDRESS_MASK) as PhysicalAddress;
+
+	let mut local_apic = LocalApic {
+		base: map_physical_region(physical, 0x400, WRITABLE | NO_CACHE | WRITE_THROUGH),
+		timer_ticks_per_ms: 0
+	};
+	local_apic.enable();
+	local_apic.calibrate_timer();
+	local_apic.start_timer(IRQ_BASE + TIMER_IRQ, TIMER_FREQUENCY);
+
+	let mut io_apic = IoApic::new(io_apic_address, gsi_base);
+	for (irq, route) in IRQ_ROUTES.iter().enumerate() {
+		if irq != TIMER_IRQ && irq != CASCADE_IRQ && io_apic.handles(route.gsi) {
+			io_apic.route(route.gsi, IRQ_BASE + irq, local_apic.id(), route.active_low, route.level_triggered);
+		}
+	}
+
+	println!("APIC: local APIC {} at {:#x}, timer {} ticks/ms, I/O APIC at {:#x} with {} inputs",
+		local_apic.id(), physical, local_apic.timer_ticks_per_ms, io_apic_address, io_apic.redirection_entries);
+
+	LOCAL_APIC = Some(local_apic);
+	IO_APIC = Some(io_apic);
+}
+
+//Set once init_apic has run
+pub fn apic_enabled() -> bool {
+	unsafe { LOCAL_APIC.is_some() }
+}
+
+//Every CPU reaches its own local APIC at the same address, so this works on any of them
+pub fn local_apic() -> &'static LocalApic {
+	unsafe { LOCAL_APIC.as_ref().expect("local APIC not initialised") }
+}
+
+//Enables the local APIC of an application processor. It gets no timer and no I/O APIC IRQs yet.
+pub fn init_ap_apic() {
+	unsafe {
+		let apic_base = x86::rdmsr(IA32_APIC_BASE);
+		x86::wrmsr(IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+		local_apic().enable();
+	}
+}
+
+pub unsafe fn end_of_interrupt() {
+	local_apic().end_of_interrupt();
+}
+
+//Masks or unmasks an ISA IRQ (0-15) at the I/O APIC, or the LAPIC timer for IRQ 0
+pub unsafe fn set_masked(irq: usize, masked: bool) {
+	if irq == CASCADE_IRQ {
+		return;
+	}
+	if irq == TIMER_IRQ {
+		local_apic().set_timer_masked(masked);
+		return;
+	}
+	let gsi = IRQ_ROUTES[irq].gsi;
+	let io_apic = IO_APIC.as_mut().expect("I/O APIC not initialised");
+	if io_apic.handles(gsi) {
+		io_apic.set_masked(gsi, masked);
+	}
+}
@@ -1,5 +1,6 @@
 pub mod port;
 mod pic;
+pub mod apic;
 mod pci;
 pub mod ide;
 pub mod ide_disk;
@@ -13,17 +14,70 @@ pub use io::keyboard::{Keyboard, KeyEvent};
 pub use io::timer::handle_timer_interrupt;
 pub use io::ide_disk::IdeDisk;
 pub use io::membuffer::MemBuffer;
+use interrupts::{Regs, register_irq_handler};
+use sync::IrqMutex;
 
-pub static mut PICS: Pics = unsafe { Pics::new() };
-pub static mut KEYBOARD: Keyboard = Keyboard::new();
+//Both are used from fault_handler, so they have to keep interrupts off while normal code holds them
+pub static PICS: IrqMutex<Pics> = IrqMutex::new(unsafe { Pics::new() });
+pub static KEYBOARD: IrqMutex<Keyboard> = IrqMutex::new(Keyboard::new());
+//Set once by init_io before interrupts are enabled and only read after that
+static mut USING_APIC: bool = false;
+
+const TIMER_IRQ: usize = 0;
+const KEYBOARD_IRQ: usize = 1;
 
 pub fn init_io() {
 	unsafe {
-		PICS.init();
-		self::timer::init_timer();
+		PICS.lock().init();
+		if apic::apic_supported() {
+			PICS.lock().disable();
+			let (io_apic_address, gsi_base) = ::acpi::madt()
+				.and_then(apic::configure_from_madt)
+				.unwrap_or((apic::DEFAULT_IO_APIC_ADDRESS, 0));
+			apic::init_apic(io_apic_address, gsi_base);
+			USING_APIC = true;
+		} else {
+			self::timer::init_timer();
+		}
+		register_irq_handler(TIMER_IRQ, timer_interrupt).expect("timer IRQ taken");
+		register_irq_handler(KEYBOARD_IRQ, keyboard_interrupt).expect("keyboard IRQ taken");
 		//Enable interrupts
 		asm!("sti");
-		KEYBOARD.init_keyboard();
+		KEYBOARD.lock().init_keyboard();
 		self::pci::init_pci();
 	}
 }
+
+//Acknowledges an IRQ (0-15) on whichever interrupt controller delivered it
+pub unsafe fn end_of_interrupt(irq: usize) {
+	if USING_APIC {
+		apic::end_of_interrupt();
+	} else {
+		PICS.lock().end_of_interrupt(irq as u8);
+	}
+}
+
+//Is this a spurious 8259 IRQ that has to be ignored without an EOI? The I/O APIC has none.
+pub unsafe fn is_spurious_irq(irq: usize) -> bool {
+	!USING_APIC && PICS.lock().is_spurious(irq as u8)
+}
+
+//Masks or unmasks an IRQ (0-15) on the active interrupt controller
+pub unsafe fn set_irq_masked(irq: usize, masked: bool) {
+	if USING_APIC {
+		apic::set_masked(irq, masked);
+	} else {
+		PICS.lock().set_masked(irq as u8, masked);
+	}
+}
+
+fn timer_interrupt(_regs: &Regs) {
+	handle_timer_interrupt();
+}
+
+fn keyboard_interrupt(_regs: &Regs) {
+	let key_event = KEYBOARD.lock().handle_keyboard_interrupt();
+	if key_event.pressed && key_event.character != '\0' {
+		::vga_buffer::WRITER.lock().write_byte(key_event.character as u8);
+	}
+}
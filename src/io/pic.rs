@@ -18,6 +18,9 @@ const ICW1_INIT : u8 = 0x10;/* Initialization - required! */
 
 const ICW4_8086 : u8 = 0x01;/* 8086/88 (MCS-80/85) mode */
 
+const PIC_EOI : u8 = 0x20;/* End-of-interrupt command code */
+const OCW3_READ_ISR : u8 = 0x0B;/* Next command port read returns the in-service register */
+
 impl Pics {
 	pub const unsafe fn new() -> Pics {
 		Pics {
@@ -62,4 +65,50 @@ impl Pics {
 		self.master.data.write(saved_mask1);
 		self.slave.data.write(saved_mask2);
 	}
+
+	//Acknowledges an IRQ (0-15) - IRQs from the slave have to be acknowledged on both PICs
+	pub unsafe fn end_of_interrupt(&mut self, irq: u8) {
+		if irq >= 8 {
+			self.slave.command.write(PIC_EOI);
+		}
+		self.master.command.write(PIC_EOI);
+	}
+
+	//IRQ 7 and 15 also arrive when the IRQ that was raised went away before the CPU took it. Such a
+	//spurious IRQ isn't in service and must not be acknowledged, except that the master did see a
+	//real cascade IRQ for a spurious IRQ 15.
+	// http://wiki.osdev.org/8259_PIC#Spurious_IRQs
+	pub unsafe fn is_spurious(&mut self, irq: u8) -> bool {
+		if irq != 7 && irq != 15 {
+			return false;
+		}
+		let spurious = {
+			let pic = if irq < 8 { &mut self.master } else { &mut self.slave };
+			pic.command.write(OCW3_READ_ISR);
+			pic.command.read() & (1 << (irq % 8)) == 0
+		};
+		if spurious && irq == 15 {
+			self.master.command.write(PIC_EOI);
+		}
+		spurious
+	}
+
+	//Masks every IRQ so the PICs stay quiet once the APIC has taken over. Remapping them in init first
+	//keeps any interrupt that still slips through away from the exception vectors.
+	pub unsafe fn disable(&mut self) {
+		self.master.data.write(0xFF);
+		self.slave.data.write(0xFF);
+	}
+
+	//Masks or unmasks a single IRQ (0-15)
+	pub unsafe fn set_masked(&mut self, irq: u8, masked: bool) {
+		let pic = if irq < 8 { &mut self.master } else { &mut self.slave };
+		let bit = 1 << (irq % 8);
+		let mask = pic.data.read();
+		pic.data.write(if masked { mask | bit } else { mask & !bit });
+		//the slave only gets through while the cascade IRQ on the master is unmasked
+		if irq >= 8 && !masked {
+			self.set_masked(2, false);
+		}
+	}
 }
@@ -14,14 +14,8 @@ pub fn init_timer()
 	}*/
 }
 
-static mut timer_ticks : u64 = 0;
-
 pub fn handle_timer_interrupt()
 {
-	unsafe { 
-		timer_ticks = timer_ticks + 1;
-		if timer_ticks % 18 == 0 {
-			//println!("Tick");
-		}
-	}
+	//each CPU counts the ticks of its own timer
+	::percpu::current().count_timer_tick();
 }
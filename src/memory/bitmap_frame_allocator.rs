@@ -0,0 +1,159 @@
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use multiboot2::MemoryAreaIter;
+
+//One bit per frame, enough to track the first 4 GiB of physical memory
+const MAX_FRAMES: usize = 1024 * 1024;
+const BITS: usize = 64;
+
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / BITS] = [0; MAX_FRAMES / BITS];
+//A set bit means the frame lies in an available memory area - only those are ever freed
+static mut FRAME_AVAILABLE: [u64; MAX_FRAMES / BITS] = [0; MAX_FRAMES / BITS];
+//Number of owners of each frame beyond the first (see share_frame)
+static mut FRAME_SHARES: [u8; MAX_FRAMES] = [0; MAX_FRAMES];
+static mut FRAME_BITMAP_TAKEN: bool = false;
+
+//Tracks every usable frame from the multiboot memory map - a set bit means the frame is in use
+pub struct BitmapFrameAllocator {
+	bitmap: &'static mut [u64; MAX_FRAMES / BITS],
+	available: &'static mut [u64; MAX_FRAMES / BITS],
+	shares: &'static mut [u8; MAX_FRAMES],
+	total_frames: usize,
+	used_frames: usize,
+	next_free_word: usize,
+}
+
+impl BitmapFrameAllocator {
+	pub fn new(kernel_start: usize, kernel_end: usize,
+		multiboot_start: usize, multiboot_end: usize,
+		memory_areas: MemoryAreaIter) -> BitmapFrameAllocator
+	{
+		let (bitmap, available, shares) = unsafe {
+			assert!(!FRAME_BITMAP_TAKEN, "only one bitmap frame allocator can exist");
+			FRAME_BITMAP_TAKEN = true;
+			(&mut FRAME_BITMAP, &mut FRAME_AVAILABLE, &mut FRAME_SHARES)
+		};
+
+		//everything starts out used, then the available areas are freed
+		for word in bitmap.iter_mut() {
+			*word = !0;
+		}
+		let mut allocator = BitmapFrameAllocator {
+			bitmap: bitmap,
+			available: available,
+			shares: shares,
+			total_frames: 0,
+			used_frames: 0,
+			next_free_word: 0,
+		};
+
+		for area in memory_areas {
+			//only whole frames inside the area can be used
+			let first = Frame::containing_address((area.base_addr as usize) + PAGE_SIZE - 1);
+			let end = Frame::containing_address((area.base_addr + area.length) as usize);
+			for number in first.number..end.number {
+				if number < MAX_FRAMES && allocator.is_used(number) {
+					allocator.available[number / BITS] |= 1 << (number % BITS);
+					allocator.set_free(number);
+					allocator.total_frames += 1;
+				}
+			}
+		}
+
+		//the multiboot information may start in the kernel's last frame
+		allocator.mark_used(Frame::containing_address(kernel_start), Frame::containing_address(kernel_end));
+		allocator.mark_used(Frame::containing_address(multiboot_start), Frame::containing_address(multiboot_end));
+		allocator
+	}
+
+	fn is_used(&self, number: usize) -> bool {
+		self.bitmap[number / BITS] & (1 << (number % BITS)) != 0
+	}
+
+	fn set_used(&mut self, number: usize) {
+		self.bitmap[number / BITS] |= 1 << (number % BITS);
+	}
+
+	fn set_free(&mut self, number: usize) {
+		self.bitmap[number / BITS] &= !(1 << (number % BITS));
+	}
+
+	//Marks a range of frames as used so they are never handed out. Memory the allocator doesn't own
+	//(device memory, ROM) can be reserved any number of times, RAM only while it is free.
+	pub fn reserve_range(&mut self, start: Frame, end: Frame) -> Result<(), &'static str> {
+		if Frame::range_inclusive(start.clone(), end.clone()).any(|frame| self.owns_frame(&frame) && self.is_used(frame.number)) {
+			return Err("Frame range overlaps memory that is in use");
+		}
+		self.mark_used(start, end);
+		Ok(())
+	}
+
+	fn mark_used(&mut self, start: Frame, end: Frame) {
+		for frame in Frame::range_inclusive(start, end) {
+			if frame.number < MAX_FRAMES && !self.is_used(frame.number) {
+				self.set_used(frame.number);
+				self.used_frames += 1;
+			}
+		}
+	}
+
+	pub fn total_frames(&self) -> usize {
+		self.total_frames
+	}
+
+	pub fn used_frames(&self) -> usize {
+		self.used_frames
+	}
+
+	pub fn free_frames(&self) -> usize {
+		self.total_frames - self.used_frames
+	}
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+	fn allocate_frame(&mut self) -> Option<Frame> {
+		for word_idx in self.next_free_word..self.bitmap.len() {
+			let word = self.bitmap[word_idx];
+			if word != !0 {
+				let number = word_idx * BITS + (!word).trailing_zeros() as usize;
+				self.set_used(number);
+				self.used_frames += 1;
+				self.next_free_word = word_idx;
+				return Some(Frame { number: number });
+			}
+		}
+		None //out of frames!
+	}
+
+	fn deallocate_frame(&mut self, frame: Frame) {
+		assert!(self.owns_frame(&frame), "freeing frame {:?} outside of the available memory", frame);
+		assert!(self.is_used(frame.number), "double free of frame {:?}", frame);
+		if self.shares[frame.number] > 0 {
+			//still owned by someone else
+			self.shares[frame.number] -= 1;
+			return;
+		}
+		self.set_free(frame.number);
+		self.used_frames -= 1;
+
+		let word_idx = frame.number / BITS;
+		if word_idx < self.next_free_word {
+			self.next_free_word = word_idx;
+		}
+	}
+
+	fn share_frame(&mut self, frame: &Frame) {
+		assert!(self.owns_frame(frame), "sharing frame {:?} outside of the available memory", frame);
+		assert!(self.is_used(frame.number), "sharing free frame {:?}", frame);
+		assert!(self.shares[frame.number] < 255, "frame {:?} has too many owners", frame);
+		self.shares[frame.number] += 1;
+	}
+
+	fn is_shared(&self, frame: &Frame) -> bool {
+		frame.number < MAX_FRAMES && self.shares[frame.number] > 0
+	}
+
+	//Only frames inside the available memory areas, not e.g. the VGA buffer, ROM or MMIO
+	fn owns_frame(&self, frame: &Frame) -> bool {
+		frame.number < MAX_FRAMES && self.available[frame.number / BITS] & (1 << (frame.number % BITS)) != 0
+	}
+}
@@ -0,0 +1,17 @@
+use memory::MEMORY;
+use memory::fault::PageFaultError;
+use memory::page::{Page, VirtualAddress};
+
+//Resolves a write to a copy-on-write page (see InactivePageTable::clone_user_half and
+//PageTable::resolve_cow_fault)
+pub fn handle_cow_fault(address: VirtualAddress, error: PageFaultError) -> bool {
+	let mut lock = match MEMORY.try_lock() {
+		Some(lock) => lock,
+		None => return false,
+	};
+	let memory = match lock.as_mut() {
+		Some(memory) => memory,
+		None => return false,
+	};
+	memory.active_table.resolve_cow_fault(Page::containing_address(address), error, &mut memory.frame_allocator)
+}
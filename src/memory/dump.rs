@@ -0,0 +1,164 @@
+use memory::{Frame, PAGE_SIZE, phys_to_virt};
+use memory::page::{Page, PhysicalAddress, VirtualAddress};
+use memory::pagetable::ENTRY_COUNT;
+use memory::table::{Table, Level4, Level1};
+use memory::entry::*;
+use core::fmt;
+
+//Lists what a page table hierarchy maps. The tables are read through the physical memory mapping
+//and nothing is locked, so this works on inactive tables and from inside the page fault handler.
+
+//A run of pages with the same page size and flags that map contiguous physical memory
+#[derive(Clone, Copy, Debug)]
+pub struct Mapping {
+	pub start: VirtualAddress,
+	pub end: VirtualAddress,
+	pub phys: PhysicalAddress,
+	pub page_size: usize,
+	//effective flags - WRITABLE and USER_ACCESSIBLE only if every level allows it, NO_EXECUTE if any level sets it
+	pub flags: EntryFlags,
+}
+
+impl Mapping {
+	fn extends(&self, next: &Mapping) -> bool {
+		self.end == next.start &&
+			self.phys + (self.end - self.start) == next.phys &&
+			self.page_size == next.page_size &&
+			self.flags == next.flags
+	}
+}
+
+impl fmt::Display for Mapping {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let size = match self.page_size {
+			0x1000 => "4K",
+			0x200000 => "2M",
+			_ => "1G",
+		};
+		try!(write!(f, "{:#018x}-{:#018x} -> {:#012x} {} r{}{} {}{}",
+			self.start, self.end, self.phys, size,
+			if self.flags.contains(WRITABLE) { "w" } else { "-" },
+			if self.flags.contains(NO_EXECUTE) { "-" } else { "x" },
+			if self.flags.contains(USER_ACCESSIBLE) { "user" } else { "kernel" },
+			if self.flags.contains(GLOBAL) { " global" } else { "" }));
+		if self.flags.contains(NO_CACHE) {
+			try!(write!(f, " uncached"));
+		}
+		if self.flags.contains(WRITE_THROUGH) {
+			try!(write!(f, " write-through"));
+		}
+		if self.flags.contains(COPY_ON_WRITE) {
+			try!(write!(f, " cow"));
+		}
+		Ok(())
+	}
+}
+
+fn table_at<'a>(frame: &Frame) -> &'a Table<Level1> {
+	unsafe { &*(phys_to_virt(frame.start_address()) as *const Table<Level1>) }
+}
+
+fn sign_extend(address: usize) -> usize {
+	if address & (1 << 47) != 0 {
+		address | 0xffff_0000_0000_0000
+	} else {
+		address
+	}
+}
+
+fn effective_flags(parent: EntryFlags, entry: EntryFlags) -> EntryFlags {
+	//accessed and dirty bits would split every range into pieces
+	let mut flags = entry - (ACCESSED | DIRTY | HUGE_PAGE);
+	if !parent.contains(WRITABLE) {
+		flags.remove(WRITABLE);
+	}
+	if !parent.contains(USER_ACCESSIBLE) {
+		flags.remove(USER_ACCESSIBLE);
+	}
+	flags | (parent & NO_EXECUTE)
+}
+
+fn walk_table<F>(table: &Table<Level1>, level: usize, start: VirtualAddress, parent_flags: EntryFlags, f: &mut F)
+	where F : FnMut(Mapping) {
+	let entry_size = PAGE_SIZE << (9 * (level - 1));
+	for index in 0..ENTRY_COUNT {
+		let entry = &table[index];
+		let frame = match entry.pointed_frame() {
+			Some(frame) => frame,
+			None => continue,
+		};
+		let address = sign_extend(start + index * entry_size);
+		let flags = effective_flags(parent_flags, entry.flags());
+		if level == 1 || entry.flags().contains(HUGE_PAGE) {
+			f(Mapping {
+				start: address,
+				end: address.wrapping_add(entry_size),
+				phys: frame.start_address(),
+				page_size: entry_size,
+				flags: flags,
+			});
+		} else {
+			walk_table(table_at(&frame), level - 1, address, flags, f);
+		}
+	}
+}
+
+//Calls f for every mapped range of the hierarchy rooted at p4, in address order
+pub fn walk_page_table<F>(p4: &Table<Level4>, mut f: F) where F : FnMut(Mapping) {
+	let p4 = unsafe { &*(p4 as *const _ as *const Table<Level1>) };
+	let mut current: Option<Mapping> = None;
+	walk_table(p4, 4, 0, PRESENT | WRITABLE | USER_ACCESSIBLE, &mut |mapping: Mapping| {
+		let merged = match current {
+			Some(ref range) => range.extends(&mapping),
+			None => false,
+		};
+		if merged {
+			current.as_mut().unwrap().end = mapping.end;
+		} else {
+			if let Some(range) = current {
+				f(range);
+			}
+			current = Some(mapping);
+		}
+	});
+	if let Some(range) = current {
+		f(range);
+	}
+}
+
+pub fn dump_page_table(p4: &Table<Level4>) {
+	walk_page_table(p4, |mapping| println!("{}", mapping));
+}
+
+//Prints every mapping of the address space in CR3
+pub fn dump_active_page_table() {
+	let p4_frame = Frame::containing_address(unsafe { ::x86::cr3() } as usize);
+	let p4 = unsafe { &*(phys_to_virt(p4_frame.start_address()) as *const Table<Level4>) };
+	dump_page_table(p4);
+}
+
+//Prints the entry for address at every level of the active hierarchy, down to the first entry
+//that is missing or maps a huge page
+pub fn dump_page_walk(address: VirtualAddress) {
+	let page = Page::containing_address(address);
+	let p4_frame = Frame::containing_address(unsafe { ::x86::cr3() } as usize);
+	let mut table = table_at(&p4_frame);
+	let indexes = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+	for (i, &index) in indexes.iter().enumerate() {
+		let level = 4 - i;
+		let entry = &table[index];
+		match entry.pointed_frame() {
+			Some(frame) => {
+				println!("  P{}[{}]: {:#x} {:?}", level, index, frame.start_address(), entry.flags());
+				if entry.flags().contains(HUGE_PAGE) {
+					break;
+				}
+				table = table_at(&frame);
+			},
+			None => {
+				println!("  P{}[{}]: not present", level, index);
+				break;
+			},
+		}
+	}
+}
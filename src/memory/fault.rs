@@ -0,0 +1,35 @@
+use memory::page::VirtualAddress;
+use memory::dump::dump_page_walk;
+
+//The error code the CPU pushes for a page fault
+// http://wiki.osdev.org/Exceptions#Page_Fault
+bitflags! {
+	flags PageFaultError: u64 {
+		//set if the page was present and the access wasn't allowed, clear if it wasn't present
+		const PROTECTION_VIOLATION = 1 << 0,
+		const CAUSED_BY_WRITE = 1 << 1,
+		const USER_MODE = 1 << 2,
+		//a reserved bit was set in one of the table entries
+		const MALFORMED_TABLE = 1 << 3,
+		const INSTRUCTION_FETCH = 1 << 4,
+	}
+}
+
+//Prints why the access to address faulted and how the active tables map it
+pub fn print_page_fault(address: VirtualAddress, error_code: u64) {
+	let error = PageFaultError::from_bits_truncate(error_code);
+	let access = if error.contains(INSTRUCTION_FETCH) {
+		"instruction fetch"
+	} else if error.contains(CAUSED_BY_WRITE) {
+		"write"
+	} else {
+		"read"
+	};
+	println!("Page fault at {:#x}: {} {} in {} mode{} (error code {:#x})", address,
+		if error.contains(PROTECTION_VIOLATION) { "protection violation on" } else { "page not present for" },
+		access,
+		if error.contains(USER_MODE) { "user" } else { "kernel" },
+		if error.contains(MALFORMED_TABLE) { ", reserved bit set in a table entry" } else { "" },
+		error_code);
+	dump_page_walk(address);
+}
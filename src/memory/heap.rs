@@ -0,0 +1,217 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use sync::IrqMutex;
+use memory::{PAGE_SIZE, MEMORY};
+use memory::page::Page;
+use memory::entry::{WRITABLE, NO_EXECUTE};
+use memory::slab::{fits_slab_cache, slab_alloc, slab_free};
+
+pub const HEAP_START: usize = 0o_177777_600_000_000_000_0000; // P4 entry 384, in the kernel half
+pub const HEAP_INITIAL_SIZE: usize = 16 * PAGE_SIZE;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+#[cfg_attr(not(test), global_allocator)]
+pub static HEAP_ALLOCATOR: HeapAllocator = HeapAllocator::new();
+
+//A free block of heap memory - stored inside the free memory itself
+struct Hole {
+	size: usize,
+	next: *mut Hole
+}
+
+const HOLE_SIZE: usize = 16;
+
+fn align_up(addr: usize, align: usize) -> usize {
+	(addr + align - 1) & !(align - 1)
+}
+
+//First fit allocator over an address ordered list of holes
+pub struct Heap {
+	start: usize,
+	size: usize,
+	free: usize,
+	head: Hole
+}
+
+// the hole list only points into the heap region owned by this Heap
+unsafe impl Send for Heap {}
+
+impl Heap {
+	pub const fn empty() -> Heap {
+		Heap {
+			start: 0,
+			size: 0,
+			free: 0,
+			head: Hole { size: 0, next: 0 as *mut Hole }
+		}
+	}
+
+	//every allocation is rounded up so that a freed block is always big enough to become a hole
+	fn hole_layout(layout: &Layout) -> (usize, usize) {
+		let align = if layout.align() > HOLE_SIZE { layout.align() } else { HOLE_SIZE };
+		let size = if layout.size() > HOLE_SIZE { layout.size() } else { HOLE_SIZE };
+		(align_up(size, HOLE_SIZE), align)
+	}
+
+	unsafe fn init(&mut self, start: usize, size: usize) {
+		assert!(mem::size_of::<Hole>() <= HOLE_SIZE);
+		self.start = start;
+		self.size = size;
+		self.free = 0;
+		self.add_free_region(start, size);
+	}
+
+	//Adds the memory directly after the end of the heap
+	unsafe fn extend(&mut self, size: usize) {
+		let end = self.start + self.size;
+		self.size += size;
+		self.add_free_region(end, size);
+	}
+
+	unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+		let head: *mut Hole = &mut self.head;
+
+		//find the hole to insert after to keep the list in address order
+		let mut prev = head;
+		while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+			prev = (*prev).next;
+		}
+
+		let next = (*prev).next;
+		let hole = addr as *mut Hole;
+		ptr::write(hole, Hole { size: size, next: next });
+		(*prev).next = hole;
+
+		//merge with the following hole
+		if !next.is_null() && addr + size == next as usize {
+			(*hole).size += (*next).size;
+			(*hole).next = (*next).next;
+		}
+		//merge with the preceding hole
+		if prev != head && prev as usize + (*prev).size == addr {
+			(*prev).size += (*hole).size;
+			(*prev).next = (*hole).next;
+		}
+		self.free += size;
+	}
+
+	fn allocate(&mut self, layout: &Layout) -> Option<*mut u8> {
+		let (size, align) = Heap::hole_layout(layout);
+		unsafe {
+			let mut prev: *mut Hole = &mut self.head;
+			while !(*prev).next.is_null() {
+				let hole = (*prev).next;
+				let hole_start = hole as usize;
+				let hole_end = hole_start + (*hole).size;
+				let alloc_start = align_up(hole_start, align);
+				let alloc_end = alloc_start + size;
+
+				if alloc_end <= hole_end {
+					//everything is HOLE_SIZE aligned so any space left at either side can become a new hole
+					let next = (*hole).next;
+					let mut link = prev;
+					if alloc_start > hole_start {
+						let front = hole_start as *mut Hole;
+						(*front).size = alloc_start - hole_start;
+						(*link).next = front;
+						link = front;
+					}
+					if hole_end > alloc_end {
+						let back = alloc_end as *mut Hole;
+						ptr::write(back, Hole { size: hole_end - alloc_end, next: ptr::null_mut() });
+						(*link).next = back;
+						link = back;
+					}
+					(*link).next = next;
+
+					self.free -= size;
+					return Some(alloc_start as *mut u8);
+				}
+				prev = hole;
+			}
+		}
+		None
+	}
+
+	unsafe fn deallocate(&mut self, ptr: *mut u8, layout: &Layout) {
+		let (size, _) = Heap::hole_layout(layout);
+		self.add_free_region(ptr as usize, size);
+	}
+
+	//Maps enough new pages at the end of the heap to fit at least min_size bytes. If memory runs out
+	//halfway, the pages mapped so far are unmapped again and the heap stays as it was.
+	fn grow(&mut self, min_size: usize) -> bool {
+		let size = align_up(min_size, PAGE_SIZE);
+		if self.size + size > HEAP_MAX_SIZE {
+			return false;
+		}
+
+		let start_page = Page::containing_address(self.start + self.size);
+		let end_page = Page::containing_address(self.start + self.size + size - 1);
+		{
+			let mut lock = MEMORY.lock();
+			let memory = lock.as_mut().expect("memory not initialised");
+			for page in Page::range_inclusive(start_page, end_page) {
+				if memory.active_table.try_map(page, WRITABLE | NO_EXECUTE, &mut memory.frame_allocator).is_err() {
+					for mapped in Page::range_inclusive(start_page, end_page).take_while(|mapped| mapped.start_address() < page.start_address()) {
+						memory.active_table.unmap(mapped, &mut memory.frame_allocator);
+					}
+					return false;
+				}
+			}
+		}
+		unsafe { self.extend(size); }
+		true
+	}
+}
+
+//Interrupt handlers may allocate too, so the heap keeps interrupts off while it is locked
+pub struct HeapAllocator {
+	heap: IrqMutex<Heap>
+}
+
+impl HeapAllocator {
+	pub const fn new() -> HeapAllocator {
+		HeapAllocator {
+			heap: IrqMutex::new(Heap::empty())
+		}
+	}
+
+	//Hands the already mapped region [start, start + size) to the allocator
+	pub unsafe fn init(&self, start: usize, size: usize) {
+		self.heap.lock().init(start, size);
+	}
+
+	//Returns the mapped size of the heap and how much of it is free
+	pub fn stats(&self) -> (usize, usize) {
+		let heap = self.heap.lock();
+		(heap.size, heap.free)
+	}
+}
+
+//Small objects come from the slab caches, which keeps them from fragmenting the heap
+unsafe impl GlobalAlloc for HeapAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if fits_slab_cache(&layout) {
+			return slab_alloc(layout.size()).unwrap_or(ptr::null_mut());
+		}
+		let mut heap = self.heap.lock();
+		loop {
+			if let Some(ptr) = heap.allocate(&layout) {
+				return ptr;
+			}
+			//no hole is big enough - map more of the heap (the alignment may waste a little of it)
+			let (size, align) = Heap::hole_layout(&layout);
+			if !heap.grow(size + align) {
+				return ptr::null_mut();
+			}
+		}
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if fits_slab_cache(&layout) {
+			return slab_free(ptr, layout.size());
+		}
+		self.heap.lock().deallocate(ptr, &layout);
+	}
+}
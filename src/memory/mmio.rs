@@ -0,0 +1,127 @@
+use memory::{Frame, MEMORY, PAGE_SIZE};
+use memory::page::{Page, PhysicalAddress, VirtualAddress};
+use memory::pagetable::HUGE_PAGE_SIZE_1GIB;
+use memory::entry::*;
+
+//Device memory (APIC, HPET, AHCI, framebuffers...) is mapped into its own window in the kernel
+//half. Unmapped ranges are handed out again, so short lived mappings like the ones used to read
+//the ACPI tables don't use up the window.
+pub const MMIO_START: VirtualAddress = 0o_177777_620_000_000_000_0000; // P4 entry 400
+pub const MMIO_SIZE: usize = HUGE_PAGE_SIZE_1GIB * 512;
+
+const MAX_FREE_RANGES: usize = 16;
+
+//Which parts of the MMIO window are in use - everything below next, minus the free ranges
+pub struct MmioSpace {
+	next: VirtualAddress,
+	free: [Option<(VirtualAddress, usize)>; MAX_FREE_RANGES],
+}
+
+impl MmioSpace {
+	pub fn new() -> MmioSpace {
+		MmioSpace {
+			next: MMIO_START,
+			free: [None; MAX_FREE_RANGES],
+		}
+	}
+
+	//First fit from the free ranges, then from the end of the used part of the window
+	fn allocate(&mut self, size: usize) -> Option<VirtualAddress> {
+		for slot in self.free.iter_mut() {
+			if let Some((start, free_size)) = *slot {
+				if free_size >= size {
+					*slot = if free_size > size { Some((start + size, free_size - size)) } else { None };
+					return Some(start);
+				}
+			}
+		}
+		if self.next + size > MMIO_START + MMIO_SIZE {
+			return None;
+		}
+		self.next += size;
+		Some(self.next - size)
+	}
+
+	//If every free slot is taken the range is simply lost, which the size of the window allows
+	fn free(&mut self, start: VirtualAddress, size: usize) {
+		if start + size == self.next {
+			self.next = start;
+		} else if let Some(slot) = self.free.iter_mut().find(|slot| slot.is_none()) {
+			*slot = Some((start, size));
+		}
+	}
+}
+
+//Maps size bytes of device memory at phys and returns the virtual address of phys. Registers want
+//flags of WRITABLE | NO_CACHE | WRITE_THROUGH, a framebuffer WRITABLE | WRITE_THROUGH.
+//The frames are reserved so the frame allocator never hands them out - mapping RAM that is already
+//in use is a bug and panics.
+pub fn map_physical_region(phys: PhysicalAddress, size: usize, flags: EntryFlags) -> VirtualAddress {
+	let start = phys & !(PAGE_SIZE - 1);
+	let end = (phys + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+
+	let virt = memory.mmio_space.allocate(end - start).expect("MMIO window is full");
+
+	memory.frame_allocator.reserve_range(Frame::containing_address(start), Frame::containing_address(end - 1))
+		.expect("device memory overlaps RAM that is in use");
+	memory.active_table.map_range_to(Page::containing_address(virt), Frame::containing_address(start),
+		end - start, flags | NO_EXECUTE, &mut memory.frame_allocator);
+
+	virt + (phys - start)
+}
+
+//Unmaps a region returned by map_physical_region and gives its virtual space back. The frames stay
+//reserved as they belong to the device.
+pub fn unmap_physical_region(virt: VirtualAddress, size: usize) {
+	let start = virt & !(PAGE_SIZE - 1);
+	let end = (virt + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+	assert!(start >= MMIO_START && end <= MMIO_START + MMIO_SIZE, "not an MMIO address");
+
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+
+	let mut address = start;
+	while address < end {
+		let page = Page::containing_address(address);
+		match memory.active_table.huge_page_size(page) {
+			Some(huge_size) if address % huge_size == 0 && address + huge_size <= end => {
+				memory.active_table.unmap_huge(page, &mut memory.frame_allocator);
+				address += huge_size;
+			},
+			_ => {
+				memory.active_table.unmap_keep_frame(page, &mut memory.frame_allocator);
+				address += PAGE_SIZE;
+			}
+		}
+	}
+	memory.mmio_space.free(start, end - start);
+}
+
+//Maps [phys, phys + size) at the same virtual addresses, for code that is running when paging gets
+//switched on (the SMP trampoline). The frames have to be reserved already.
+pub fn identity_map(phys: PhysicalAddress, size: usize, flags: EntryFlags) {
+	let start = phys & !(PAGE_SIZE - 1);
+	let end = (phys + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+	for address in (start..end).step_by(PAGE_SIZE) {
+		memory.active_table.map_to(Page::containing_address(address), Frame::containing_address(address),
+			flags, &mut memory.frame_allocator);
+	}
+}
+
+//Removes a mapping made by identity_map and frees the page tables it needed
+pub fn identity_unmap(phys: PhysicalAddress, size: usize) {
+	let start = phys & !(PAGE_SIZE - 1);
+	let end = (phys + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+	for address in (start..end).step_by(PAGE_SIZE) {
+		memory.active_table.unmap_keep_frame(Page::containing_address(address), &mut memory.frame_allocator);
+	}
+}
@@ -1,77 +1,212 @@
-mod area_frame_allocator;
-mod table;
-mod page;
-mod entry;
-mod pagetable;
-
-pub use self::area_frame_allocator::AreaFrameAllocator;
-use self::pagetable::remap_kernel;
-use self::page::PhysicalAddress;
-use multiboot2::BootInformation;
-
-pub const PAGE_SIZE: usize = 4096;
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Frame {
-	number: usize,
-}
-
-impl Frame {
-	fn containing_address(address: usize) -> Frame {
-		Frame{ number: address / PAGE_SIZE }
-	}
-	fn start_address(&self) -> PhysicalAddress {
-		self.number * PAGE_SIZE
-	}
-	fn clone(&self) -> Frame {
-		Frame { number: self.number }
-	}
-	fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
-		FrameIter {
-			start: start,
-			end: end
-		}
-	}
-}
-
-struct FrameIter {
-	start: Frame,
-	end: Frame,
-}
-
-impl Iterator for FrameIter {
-	type Item = Frame;
-
-	fn next(&mut self) -> Option<Frame> {
-		if self.start <= self.end {
-			let frame = self.start.clone();
-			self.start.number += 1;
-			Some(frame)
-		} else {
-			None
-		}
-	}
-}
-
-pub trait FrameAllocator {
-	fn allocate_frame(&mut self) -> Option<Frame>;
-	fn deallocate_frame(&mut self, frame: Frame);
-}
-
-pub fn init_memory(boot_info: &BootInformation, multiboot_information_address: usize) {
-	let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
-	let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf-sections tag required");
-
-	let kernel_start = elf_sections_tag.sections().map(|s| s.addr).min().unwrap();
-	let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size).max().unwrap();
-	let multiboot_start = multiboot_information_address;
-	let multiboot_end = multiboot_start + (boot_info.total_size as usize);
-	println!("kernel_start: 0x{:x}, kernel_end: 0x{:x}\nmultiboot_start: 0x{:x}, multiboot_end: 0x{:x}",
-		kernel_start, kernel_end, multiboot_start, multiboot_end);
-
-	let mut frame_allocator = AreaFrameAllocator::new(
-		kernel_start as usize, kernel_end as usize, multiboot_start, multiboot_end, memory_map_tag.memory_areas()
-	);
-	remap_kernel(&mut frame_allocator, &boot_info);
-}
-
+mod bitmap_frame_allocator;
+mod table;
+mod page;
+mod entry;
+mod pagetable;
+mod heap;
+mod region;
+mod cow;
+mod mmio;
+mod slab;
+mod dump;
+mod physical;
+mod fault;
+mod stack;
+
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::heap::{HEAP_ALLOCATOR, HEAP_START, HEAP_MAX_SIZE};
+pub use self::pagetable::{PageTable, InactivePageTable};
+pub use self::page::{Page, PhysicalAddress, VirtualAddress};
+use self::pagetable::remap_kernel;
+pub use self::entry::*;
+pub use self::region::{reserve_region, release_region};
+pub use self::mmio::{map_physical_region, unmap_physical_region, identity_map, identity_unmap};
+pub use self::stack::{Stack, KERNEL_STACK_SIZE, alloc_stack};
+pub use self::slab::{SlabCache, SlabStats, SLAB_CACHES, slab_alloc, slab_free, print_slab_stats, print_cache_stats};
+pub use self::slab::{FrameCache, BlockBuffer, BLOCK_BUFFER_SIZE, BLOCK_BUFFERS, alloc_block_buffer, free_block_buffer};
+pub use self::dump::{Mapping, walk_page_table, dump_page_table, dump_active_page_table, dump_page_walk};
+pub use self::fault::{PageFaultError, print_page_fault};
+use multiboot2::BootInformation;
+use sync::IrqMutex;
+
+pub const PAGE_SIZE: usize = 4096;
+
+//The kernel is linked at -2GiB and all of physical memory is mapped at the start of the higher half
+pub const KERNEL_OFFSET: usize = 0xffffffff_80000000;
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffff8000_00000000;
+
+//Address a physical address can be read and written through once the kernel has been remapped
+//(only the first GiB is mapped before that)
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+	address + PHYSICAL_MEMORY_OFFSET
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Frame {
+	number: usize,
+}
+
+impl Frame {
+	fn containing_address(address: usize) -> Frame {
+		Frame{ number: address / PAGE_SIZE }
+	}
+	fn start_address(&self) -> PhysicalAddress {
+		self.number * PAGE_SIZE
+	}
+	fn clone(&self) -> Frame {
+		Frame { number: self.number }
+	}
+	fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
+		FrameIter {
+			start: start,
+			end: end
+		}
+	}
+}
+
+struct FrameIter {
+	start: Frame,
+	end: Frame,
+}
+
+impl Iterator for FrameIter {
+	type Item = Frame;
+
+	fn next(&mut self) -> Option<Frame> {
+		if self.start <= self.end {
+			let frame = self.start.clone();
+			self.start.number += 1;
+			Some(frame)
+		} else {
+			None
+		}
+	}
+}
+
+pub trait FrameAllocator {
+	fn allocate_frame(&mut self) -> Option<Frame>;
+	fn deallocate_frame(&mut self, frame: Frame);
+	//Adds an owner to an allocated frame - it only becomes free once every owner has deallocated it
+	fn share_frame(&mut self, frame: &Frame);
+	//Does more than one owner hold this frame?
+	fn is_shared(&self, frame: &Frame) -> bool;
+	//Is the frame memory this allocator hands out? Device memory, ROM and the like are not.
+	fn owns_frame(&self, frame: &Frame) -> bool;
+}
+
+//The active page table and frame allocator, available once init_memory has run
+pub struct MemoryController {
+	active_table: PageTable,
+	frame_allocator: BitmapFrameAllocator,
+	mmio_space: mmio::MmioSpace,
+	next_stack_address: VirtualAddress,
+}
+
+//never allocate from the heap while holding this lock - the heap takes it to grow.
+//The page fault handler only tries to take it, as the fault may come from code that holds it.
+//Interrupts stay off while it is held, so an interrupt handler that grows the heap can't spin on it.
+pub static MEMORY: IrqMutex<Option<MemoryController>> = IrqMutex::new(None);
+
+impl MemoryController {
+	//Creates an address space with an empty user half that shares the kernel half
+	pub fn new_address_space(&mut self) -> InactivePageTable {
+		InactivePageTable::new_address_space(&self.active_table, &mut self.frame_allocator)
+	}
+
+	//Creates an address space with a copy-on-write copy of the user half of the active one
+	pub fn clone_active_address_space(&mut self) -> InactivePageTable {
+		let p4_frame = Frame::containing_address(unsafe { ::x86::cr3() } as usize);
+		InactivePageTable::clone_user_half(&p4_frame, &self.active_table, &mut self.frame_allocator)
+	}
+
+	//Creates an address space with a copy-on-write copy of the user half of source
+	pub fn clone_address_space(&mut self, source: &InactivePageTable) -> InactivePageTable {
+		InactivePageTable::clone_user_half(source.p4_frame(), &self.active_table, &mut self.frame_allocator)
+	}
+
+	//Runs f with a mapper that edits the inactive address space and the frame allocator to use with it
+	pub fn with_address_space<F>(&mut self, table: &mut InactivePageTable, f: F)
+		where F : FnOnce(&mut PageTable, &mut BitmapFrameAllocator) {
+		let frame_allocator = &mut self.frame_allocator;
+		table.with(|mapper| f(mapper, frame_allocator));
+	}
+
+	//Makes table the active address space and returns the previously active one
+	pub fn switch_address_space(&mut self, table: InactivePageTable) -> InactivePageTable {
+		self.active_table.switch(table)
+	}
+
+	//Frees the user half of an inactive address space and its P4
+	pub fn destroy_address_space(&mut self, table: InactivePageTable) {
+		table.destroy(&mut self.frame_allocator);
+	}
+}
+
+//boot_info must have been loaded through phys_to_virt, multiboot_information_address is the physical address
+pub fn init_memory(boot_info: &BootInformation, multiboot_information_address: PhysicalAddress) {
+	let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+	let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf-sections tag required");
+
+	let kernel_start = elf_sections_tag.sections().filter(|s| s.is_allocated())
+		.map(|s| pagetable::virt_to_kernel_phys(s.addr as usize)).min().unwrap();
+	let kernel_end = elf_sections_tag.sections().filter(|s| s.is_allocated())
+		.map(|s| pagetable::virt_to_kernel_phys((s.addr + s.size) as usize)).max().unwrap();
+	let multiboot_start = multiboot_information_address;
+	let multiboot_end = multiboot_start + (boot_info.total_size as usize);
+	println!("kernel_start: 0x{:x}, kernel_end: 0x{:x}\nmultiboot_start: 0x{:x}, multiboot_end: 0x{:x}",
+		kernel_start, kernel_end, multiboot_start, multiboot_end);
+
+	let mut frame_allocator = BitmapFrameAllocator::new(
+		kernel_start, kernel_end, multiboot_start, multiboot_end, memory_map_tag.memory_areas()
+	);
+	//before anything else can take them: the real mode IVT and BIOS data area, where the ACPI code
+	//finds the EBDA, and the SMP trampoline
+	let bios_data = Frame::containing_address(0);
+	frame_allocator.reserve_range(bios_data.clone(), bios_data).expect("BIOS data area frame is in use");
+	let trampoline = Frame::containing_address(::smp::TRAMPOLINE_ADDRESS);
+	frame_allocator.reserve_range(trampoline.clone(), trampoline).expect("SMP trampoline frame is in use");
+	remap_kernel(&mut frame_allocator, &boot_info);
+
+	let mut active_table = unsafe { PageTable::new_active() };
+	active_table.create_kernel_tables(&mut frame_allocator);
+
+	let heap_start_page = Page::containing_address(HEAP_START);
+	let heap_end_page = Page::containing_address(HEAP_START + heap::HEAP_INITIAL_SIZE - 1);
+	for page in Page::range_inclusive(heap_start_page, heap_end_page) {
+		active_table.map(page, WRITABLE | NO_EXECUTE, &mut frame_allocator);
+	}
+	unsafe { HEAP_ALLOCATOR.init(HEAP_START, heap::HEAP_INITIAL_SIZE); }
+
+	*MEMORY.lock() = Some(MemoryController {
+		active_table: active_table,
+		frame_allocator: frame_allocator,
+		mmio_space: mmio::MmioSpace::new(),
+		next_stack_address: stack::STACKS_START,
+	});
+
+	print_frame_stats();
+	let (heap_size, heap_free) = HEAP_ALLOCATOR.stats();
+	println!("heap: 0x{:x} size: {} KiB free: {} KiB (grows to {} KiB)",
+		HEAP_START, heap_size / 1024, heap_free / 1024, HEAP_MAX_SIZE / 1024);
+}
+
+//Called for every page fault with the error code the CPU pushed - returns true if the fault was
+//resolved and the access can be retried
+pub fn handle_page_fault(address: VirtualAddress, error_code: u64) -> bool {
+	let error = PageFaultError::from_bits_truncate(error_code);
+	cow::handle_cow_fault(address, error) || region::handle_demand_fault(address, error)
+}
+
+//Is the address inside the unmapped page below a kernel stack?
+pub fn is_stack_guard_address(address: usize) -> bool {
+	let guard_page = pagetable::stack_guard_page();
+	(address >= guard_page && address < guard_page + PAGE_SIZE) || stack::is_guard_page(address)
+}
+
+pub fn print_frame_stats() {
+	let lock = MEMORY.lock();
+	let allocator = &lock.as_ref().expect("memory not initialised").frame_allocator;
+	println!("frames: {} total, {} used, {} free ({} KiB free)",
+		allocator.total_frames(), allocator.used_frames(), allocator.free_frames(),
+		allocator.free_frames() * PAGE_SIZE / 1024);
+}
@@ -1,97 +1,258 @@
-use memory::table::{Table, Level4};
+use memory::table::{Table, Level4, Level1};
 use memory::FrameAllocator;
 use memory::page::{Page, VirtualAddress, PhysicalAddress};
 use memory::Frame;
-use memory::PAGE_SIZE;
+use memory::{PAGE_SIZE, KERNEL_OFFSET, PHYSICAL_MEMORY_OFFSET, phys_to_virt};
 use memory::entry::*;
+use memory::dump::{dump_page_table, walk_page_table};
+use memory::fault::{PageFaultError, PROTECTION_VIOLATION, CAUSED_BY_WRITE, USER_MODE};
+use memory::physical::{PhysicalMemory, PhysicalMapping};
 use multiboot2::BootInformation;
 use x86::*;
 
 pub const ENTRY_COUNT: usize = 512;
+pub const HUGE_PAGE_SIZE_2MIB: usize = PAGE_SIZE * ENTRY_COUNT;
+pub const HUGE_PAGE_SIZE_1GIB: usize = HUGE_PAGE_SIZE_2MIB * ENTRY_COUNT;
 
-struct TempPageTableMapper<'a> {
-	table: &'a mut PageTable
-}
+//First P4 entry of the kernel half - these entries are shared by every address space
+pub const KERNEL_P4_START: usize = 256;
 
-impl<'a> TempPageTableMapper<'a> {
-	// directly map the virtual memory location to the physical location in the temp page table
-	pub fn identity_map<A>(&mut self, frame: Frame, flags: EntryFlags, allocator: &mut A) where A : FrameAllocator {
-		let page = Page::containing_address(frame.start_address());
-	    self.table.map_to(page, frame, flags, allocator)
-	}
+//Gives access to a page table frame through the physical memory mapping
+unsafe fn phys_table<'a>(frame: &Frame) -> &'a mut Table<Level1> {
+	&mut *(phys_to_virt(frame.start_address()) as *mut Table<Level1>)
 }
 
-struct TempPageTable {
-	frame: Frame
+//A page table hierarchy that isn't loaded in CR3 - an address space that can be edited with
+//`with`, switched to with `PageTable::switch` and freed again with `destroy`
+pub struct InactivePageTable {
+	p4_frame: Frame
 }
 
-impl TempPageTable {
-	pub fn new_page_table<A>(page: Page, active_table: &mut PageTable, allocator: &mut A) -> TempPageTable where A : FrameAllocator {
+impl InactivePageTable {
+	//Creates an empty hierarchy
+	pub fn new<A>(allocator: &mut A) -> InactivePageTable where A : FrameAllocator {
 		let frame = allocator.allocate_frame().expect("no more frames");
-		active_table.map_to(page, frame.clone(), WRITABLE, allocator);
-		let table = unsafe { &mut *(page.start_address() as *mut Table<Level4>) };
-		table.zero();
-		table[511].set(frame.clone(), PRESENT | WRITABLE);
-		active_table.unmap(page, allocator);
+		unsafe { phys_table(&frame) }.zero();
 
-		TempPageTable {
-			frame: frame
+		InactivePageTable {
+			p4_frame: frame
 		}
 	}
 
-	pub fn with<F>(&mut self, active_table: &mut PageTable, f: F) where F : FnOnce(&mut TempPageTableMapper) {
-		let old_page = Frame::containing_address(unsafe { cr3() } as usize);
+	//Creates an address space with an empty user half that shares the kernel half with active_table
+	pub fn new_address_space<A>(active_table: &PageTable, allocator: &mut A) -> InactivePageTable where A : FrameAllocator {
+		let table = InactivePageTable::new(allocator);
+		{
+			let p4 = unsafe { phys_table(&table.p4_frame) };
+			for index in KERNEL_P4_START..ENTRY_COUNT {
+				let entry = &active_table.p4()[index];
+				if let Some(frame) = entry.pointed_frame() {
+					p4[index].set(frame, entry.flags());
+				}
+			}
+		}
+		table
+	}
 
-		active_table.p4_mut()[511].set(self.frame.clone(), PRESENT | WRITABLE);
+	//Creates a new address space sharing the kernel half with active_table, with a copy-on-write
+	//copy of every page in the user half of the hierarchy rooted at source_p4
+	pub fn clone_user_half<A>(source_p4: &Frame, active_table: &PageTable, allocator: &mut A) -> InactivePageTable
+		where A : FrameAllocator {
+		let mut table = InactivePageTable::new_address_space(active_table, allocator);
+		table.with(|mapper| mapper.share_user_half(source_p4, allocator));
+		//the source may be the active table and its writable pages just became read-only
 		unsafe { flush_tlb_all(); }
+		table
+	}
 
+	pub fn p4_frame(&self) -> &Frame {
+		&self.p4_frame
+	}
+
+	//Prints every mapping in this hierarchy
+	pub fn dump(&self) {
+		dump_page_table(unsafe { &*(phys_to_virt(self.p4_frame.start_address()) as *const Table<Level4>) });
+	}
+
+	//Runs f with a PageTable that edits this hierarchy instead of the active one
+	pub fn with<F>(&mut self, f: F) where F : FnOnce(&mut PageTable) {
+		let mut mapper = unsafe { PageTable::for_p4_frame(self.p4_frame.clone()) };
+		f(&mut mapper);
+	}
+
+	//Frees every frame of the user half - its tables and the pages they map - and the P4 itself.
+	//The kernel half is shared so it is left alone. Pages of memory the allocator doesn't own (user
+	//MMIO mappings) are only unmapped, shared frames just lose this owner.
+	pub fn destroy<A>(self, allocator: &mut A) where A : FrameAllocator {
+		assert!(self.p4_frame.start_address() != unsafe { cr3() } as usize, "can't destroy the active address space");
 		{
-			let mut mapper = TempPageTableMapper {
-				table: active_table
-			};
-			f(&mut mapper);
+			let p4 = unsafe { phys_table(&self.p4_frame) };
+			for index in 0..KERNEL_P4_START {
+				if let Some(p3_frame) = p4[index].pointed_frame() {
+					free_table(p3_frame, 3, allocator);
+				}
+			}
 		}
+		allocator.deallocate_frame(self.p4_frame);
+	}
+}
 
-		active_table.p4_mut()[511].set(old_page, PRESENT | WRITABLE);
-		unsafe { flush_tlb_all(); }
+//Number of bytes an entry in a table of the given level maps
+fn entry_size(level: usize) -> usize {
+	PAGE_SIZE << (9 * (level - 1))
+}
+
+//First frame of a 2 MiB or 1 GiB page entry, whose lowest address bit is the PAT bit
+fn huge_page_frame(frame: &Frame) -> Frame {
+	Frame::containing_address(frame.start_address() & !HUGE_PAGE_PAT)
+}
+
+//Frees a table of the given level and everything below it. Only frames the allocator owns are
+//freed - a huge page of RAM is owned frame by frame (see share_table).
+fn free_table<A>(frame: Frame, level: usize, allocator: &mut A) where A : FrameAllocator {
+	{
+		let table = unsafe { phys_table(&frame) };
+		for index in 0..ENTRY_COUNT {
+			if let Some(child) = table[index].pointed_frame() {
+				if level == 1 {
+					if allocator.owns_frame(&child) {
+						allocator.deallocate_frame(child);
+					}
+				} else if !table[index].flags().contains(HUGE_PAGE) {
+					free_table(child, level - 1, allocator);
+				} else {
+					let first = huge_page_frame(&child);
+					if allocator.owns_frame(&first) {
+						for number in first.number..first.number + entry_size(level) / PAGE_SIZE {
+							allocator.deallocate_frame(Frame { number: number });
+						}
+					}
+				}
+			}
+		}
 	}
+	allocator.deallocate_frame(frame);
+}
 
-	pub fn make_active(&mut self) -> Frame {
-		let old_table = Frame::containing_address(unsafe { cr3() } as usize);
-		unsafe { cr3_write(self.frame.start_address() as u64); }
-		old_table
+//Maps every page below the table of the given level into mapper as well, starting at the virtual
+//address the table covers. Writable pages of RAM become read-only copy-on-write pages on both sides
+//and each of their 4 KiB frames gets another owner - huge pages are split up again once they are
+//written to. Device memory is simply mapped into both.
+fn share_table<M, A>(frame: &Frame, level: usize, start: VirtualAddress, mapper: &mut PageTable<M>, allocator: &mut A)
+	where M : PhysicalMemory, A : FrameAllocator {
+	let table = unsafe { &mut *(mapper.memory.frame_address(frame) as *mut Table<Level1>) };
+	for index in 0..ENTRY_COUNT {
+		let address = start + index * entry_size(level);
+		let mut flags = table[index].flags();
+		let child = match table[index].pointed_frame() {
+			Some(child) => child,
+			None => continue,
+		};
+		if level > 1 && !flags.contains(HUGE_PAGE) {
+			share_table(&child, level - 1, address, mapper, allocator);
+			continue;
+		}
+
+		let first = if level == 1 { child.clone() } else { huge_page_frame(&child) };
+		if allocator.owns_frame(&first) {
+			if flags.contains(WRITABLE) {
+				flags = (flags - WRITABLE) | COPY_ON_WRITE;
+				table[index].set(child, flags);
+			}
+			for number in first.number..first.number + entry_size(level) / PAGE_SIZE {
+				allocator.share_frame(&Frame { number: number });
+			}
+		}
+		//the clone maps child itself so a huge page keeps its PAT bit
+		let page = Page::containing_address(address);
+		match level {
+			1 => mapper.map_to(page, child, flags, allocator),
+			2 => mapper.map_to_2mib(page, child, flags, allocator),
+			_ => mapper.map_to_1gib(page, child, flags, allocator),
+		}
 	}
 }
 
-pub struct PageTable {
-	p4: *mut Table<Level4>,
+//A page table hierarchy rooted at p4_frame. Its tables are reached through memory, which is the
+//physical memory mapping everywhere except in the host-side tests.
+pub struct PageTable<M: PhysicalMemory = PhysicalMapping> {
+	p4_frame: Frame,
+	memory: M,
+	//may map_range_to use 1 GiB pages - not every CPU has them
+	huge_pages_1gib: bool,
 }
 
 impl PageTable {
+	//The hierarchy currently loaded in CR3
 	pub unsafe fn new_active() -> PageTable {
+		PageTable::for_p4_frame(Frame::containing_address(cr3() as usize))
+	}
+
+	//Edits the hierarchy at p4_frame, using 1 GiB pages if the CPU has them
+	unsafe fn for_p4_frame(p4_frame: Frame) -> PageTable {
+		let mut table = PageTable::new(p4_frame, PhysicalMapping);
+		table.use_1gib_pages(cpuid::has_feature(cpuid::HUGE_PAGES_1GIB));
+		table
+	}
+
+	//Prints every mapping in this hierarchy
+	pub fn dump(&self) {
+		dump_page_table(self.p4());
+	}
+
+	//Loads the new hierarchy into CR3 and hands back the one that was active
+	pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
+		let old_table = InactivePageTable {
+			p4_frame: Frame::containing_address(unsafe { cr3() } as usize)
+		};
+		unsafe { cr3_write(new_table.p4_frame.start_address() as u64); }
+		self.p4_frame = new_table.p4_frame;
+		old_table
+	}
+}
+
+impl<M> PageTable<M> where M : PhysicalMemory {
+	//p4_frame must hold a valid P4 table that nothing else is editing. map_range_to sticks to 2 MiB
+	//pages until use_1gib_pages turns the bigger ones on.
+	pub unsafe fn new(p4_frame: Frame, memory: M) -> PageTable<M> {
 		PageTable {
-			p4: 0xffffffff_fffff000 as *mut _,
+			p4_frame: p4_frame,
+			memory: memory,
+			huge_pages_1gib: false,
 		}
 	}
 
+	pub fn use_1gib_pages(&mut self, enabled: bool) {
+		self.huge_pages_1gib = enabled;
+	}
+
 	fn p4(&self) -> &Table<Level4> {
-		unsafe { &*self.p4 }
+		unsafe { &*(self.memory.frame_address(&self.p4_frame) as *const Table<Level4>) }
 	}
 
 	fn p4_mut(&mut self) -> &mut Table<Level4> {
-		unsafe { &mut *self.p4 }
+		unsafe { &mut *(self.memory.frame_address(&self.p4_frame) as *mut Table<Level4>) }
+	}
+
+	//Makes sure every kernel half P4 entry points at a P3 table, so that address spaces created later
+	//see kernel mappings that are added after they were created
+	pub fn create_kernel_tables<A>(&mut self, allocator: &mut A) where A : FrameAllocator {
+		let memory = self.memory;
+		let p4 = self.p4_mut();
+		for index in KERNEL_P4_START..ENTRY_COUNT {
+			p4.next_table_create(index, memory, allocator);
+		}
 	}
 
 	//Translates a virtual page into a physical frame
 	fn translate_page(&self, page: Page) -> Option<Frame> {
-		use memory::entry::HUGE_PAGE;
-
-		let p3 = self.p4().next_table(page.p4_index());
+		let memory = self.memory;
+		let p3 = self.p4().next_table(page.p4_index(), memory);
 
 		//Start at top p4 table, lookup the p3 table, then lookup the p2 table, 
 		//then lookup the p1 table and grab the physical frame pointed to
-		p3.and_then(|p3| p3.next_table(page.p3_index()))
-			.and_then(|p2| p2.next_table(page.p2_index()))
+		p3.and_then(|p3| p3.next_table(page.p3_index(), memory))
+			.and_then(|p2| p2.next_table(page.p2_index(), memory))
 			.and_then(|p1| p1[page.p1_index()].pointed_frame()) 
 			.or_else(|| {
 				//if the PRESENT flag was missing OR we're dealing with a HUGE_PAGE for ANY of those entries
@@ -101,6 +262,7 @@ impl PageTable {
 					// 1 GiB page?
 					if let Some(start_frame) = p3_entry.pointed_frame() {
 						if p3_entry.flags().contains(HUGE_PAGE) {
+							let start_frame = Frame::containing_address(start_frame.start_address() & !HUGE_PAGE_PAT);
 							// address must be 1GiB aligned
 							assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
 							return Some(Frame {
@@ -109,10 +271,11 @@ impl PageTable {
 						}
 					}
 					// 2 MiB page?
-					if let Some(p2) = p3.next_table(page.p3_index()) {
+					if let Some(p2) = p3.next_table(page.p3_index(), memory) {
 						let p2_entry = &p2[page.p2_index()];
 						if let Some(start_frame) = p2_entry.pointed_frame() {
 							if p2_entry.flags().contains(HUGE_PAGE) {
+								let start_frame = Frame::containing_address(start_frame.start_address() & !HUGE_PAGE_PAT);
 								// address must be 2MiB aligned
 								assert!(start_frame.number % ENTRY_COUNT == 0);
 								return Some(Frame {
@@ -133,36 +296,288 @@ impl PageTable {
 			.map(|frame| frame.number * PAGE_SIZE + offset)
 	}
 
+	//Returns the size of the huge page mapping that covers this page, if there is one
+	pub fn huge_page_size(&self, page: Page) -> Option<usize> {
+		let memory = self.memory;
+		self.p4().next_table(page.p4_index(), memory).and_then(|p3| {
+			if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+				return Some(HUGE_PAGE_SIZE_1GIB);
+			}
+			p3.next_table(page.p3_index(), memory).and_then(|p2| {
+				if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+					Some(HUGE_PAGE_SIZE_2MIB)
+				} else {
+					None
+				}
+			})
+		})
+	}
+
+	//The flags of the 2 MiB or 1 GiB page entry that covers this page, if there is one
+	fn huge_page_flags(&self, page: Page) -> Option<EntryFlags> {
+		let memory = self.memory;
+		self.p4().next_table(page.p4_index(), memory).and_then(|p3| {
+			let p3_flags = p3[page.p3_index()].flags();
+			if p3_flags.contains(PRESENT | HUGE_PAGE) {
+				return Some(p3_flags);
+			}
+			p3.next_table(page.p3_index(), memory).and_then(|p2| {
+				let p2_flags = p2[page.p2_index()].flags();
+				if p2_flags.contains(PRESENT | HUGE_PAGE) {
+					Some(p2_flags)
+				} else {
+					None
+				}
+			})
+		})
+	}
+
+	//Splits the huge page that covers page, if there is one, until page has a P1 entry of its own
+	fn split_to_4kib<A>(&mut self, page: Page, allocator: &mut A) where A : FrameAllocator {
+		let memory = self.memory;
+		self.p4_mut().next_table_create(page.p4_index(), memory, allocator)
+			.next_table_create(page.p3_index(), memory, allocator)
+			.next_table_create(page.p2_index(), memory, allocator);
+	}
+
+	//Maps the user half of the hierarchy rooted at source_p4 into this one copy-on-write (see share_table)
+	pub fn share_user_half<A>(&mut self, source_p4: &Frame, allocator: &mut A) where A : FrameAllocator {
+		let source = unsafe { &*(self.memory.frame_address(source_p4) as *const Table<Level4>) };
+		for index in 0..KERNEL_P4_START {
+			if let Some(p3_frame) = source[index].pointed_frame() {
+				share_table(&p3_frame, 3, index * HUGE_PAGE_SIZE_1GIB * ENTRY_COUNT, self, allocator);
+			}
+		}
+	}
+
+	//Resolves a write to a copy-on-write page. A huge page is split first so that only the written
+	//4 KiB page gets copied. The last owner of a frame can simply make it writable again, everyone
+	//else gets their own copy of it. Returns false, leaving the fault to be reported, for anything
+	//else: a page that isn't copy-on-write, a read or instruction fetch, or user mode writing to a
+	//kernel page.
+	pub fn resolve_cow_fault<A>(&mut self, page: Page, error: PageFaultError, allocator: &mut A) -> bool
+		where A : FrameAllocator {
+		if !error.contains(PROTECTION_VIOLATION | CAUSED_BY_WRITE) {
+			return false;
+		}
+		let resolvable = |flags: EntryFlags| flags.contains(PRESENT | COPY_ON_WRITE)
+			&& (flags.contains(USER_ACCESSIBLE) || !error.contains(USER_MODE));
+		if let Some(flags) = self.huge_page_flags(page) {
+			if !resolvable(flags) {
+				return false;
+			}
+			self.split_to_4kib(page, allocator);
+		}
+		let (frame, flags) = match self.p1_entry_mut(page) {
+			Some(entry) => {
+				if !resolvable(entry.flags()) {
+					return false;
+				}
+				(entry.pointed_frame().unwrap(), entry.flags())
+			},
+			None => return false,
+		};
+		let new_flags = (flags - COPY_ON_WRITE) | WRITABLE;
+
+		let memory = self.memory;
+		let new_frame = if allocator.is_shared(&frame) {
+			let copy = match allocator.allocate_frame() {
+				Some(copy) => copy,
+				None => return false,
+			};
+			unsafe {
+				::core::ptr::copy_nonoverlapping(memory.frame_address(&frame) as *const u8,
+					memory.frame_address(&copy) as *mut u8, PAGE_SIZE);
+			}
+			//drops this address space's share of the original
+			allocator.deallocate_frame(frame);
+			copy
+		} else {
+			frame
+		};
+
+		self.p1_entry_mut(page).unwrap().set(new_frame, new_flags);
+		memory.flush(page.start_address());
+		true
+	}
+
 	//Modify the page tables to map a Page to a Physical Frame - this is going to set up a page table recursively
-	//and point the hierarchy to the physical frame address
+	//and point the hierarchy to the physical frame address. If the page is part of a huge page the huge page
+	//is split up first and the page is remapped.
 	pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A : FrameAllocator {
+		let in_huge_page = self.huge_page_size(page).is_some();
+		let memory = self.memory;
 		let mut p4 = self.p4_mut();
-		let mut p3 = p4.next_table_create(page.p4_index(), allocator);
-		let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-		let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+		let mut p3 = p4.next_table_create(page.p4_index(), memory, allocator);
+		let mut p2 = p3.next_table_create(page.p3_index(), memory, allocator);
+		let mut p1 = p2.next_table_create(page.p2_index(), memory, allocator);
 
-		assert!(p1[page.p1_index()].is_unused());
+		assert!(in_huge_page || p1[page.p1_index()].is_unused());
 		p1[page.p1_index()].set(frame, flags | PRESENT);
+		if in_huge_page {
+			memory.flush(page.start_address());
+		}
+	}
+
+	//Maps a 2 MiB page - both the page and the frame must be 2 MiB aligned, apart from the PAT bit of the frame
+	pub fn map_to_2mib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A : FrameAllocator {
+		assert!(page.start_address() % HUGE_PAGE_SIZE_2MIB == 0, "page must be 2MiB aligned");
+		assert!(huge_page_frame(&frame).start_address() % HUGE_PAGE_SIZE_2MIB == 0, "frame must be 2MiB aligned");
+		let memory = self.memory;
+		let mut p4 = self.p4_mut();
+		let mut p3 = p4.next_table_create(page.p4_index(), memory, allocator);
+		let mut p2 = p3.next_table_create(page.p3_index(), memory, allocator);
+
+		assert!(p2[page.p2_index()].is_unused());
+		p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+	}
+
+	//Maps a 1 GiB page - both the page and the frame must be 1 GiB aligned, apart from the PAT bit of the frame,
+	//and the CPU must support pdpe1gb
+	pub fn map_to_1gib<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A) where A : FrameAllocator {
+		assert!(page.start_address() % HUGE_PAGE_SIZE_1GIB == 0, "page must be 1GiB aligned");
+		assert!(huge_page_frame(&frame).start_address() % HUGE_PAGE_SIZE_1GIB == 0, "frame must be 1GiB aligned");
+		let memory = self.memory;
+		let mut p4 = self.p4_mut();
+		let mut p3 = p4.next_table_create(page.p4_index(), memory, allocator);
+
+		assert!(p3[page.p3_index()].is_unused());
+		p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+	}
+
+	//Maps size bytes of physical memory starting at frame to the virtual memory starting at page,
+	//using 1 GiB (if enabled with use_1gib_pages) or 2 MiB pages wherever both sides are aligned so
+	//large ranges need few tables
+	pub fn map_range_to<A>(&mut self, page: Page, frame: Frame, size: usize, flags: EntryFlags, allocator: &mut A)
+		where A : FrameAllocator {
+		let use_1gib_pages = self.huge_pages_1gib;
+		let mut offset = 0;
+		while offset < size {
+			let virt = page.start_address() + offset;
+			let phys = frame.start_address() + offset;
+			if use_1gib_pages && virt % HUGE_PAGE_SIZE_1GIB == 0 && phys % HUGE_PAGE_SIZE_1GIB == 0
+				&& size - offset >= HUGE_PAGE_SIZE_1GIB {
+				self.map_to_1gib(Page::containing_address(virt), Frame::containing_address(phys), flags, allocator);
+				offset += HUGE_PAGE_SIZE_1GIB;
+			} else if virt % HUGE_PAGE_SIZE_2MIB == 0 && phys % HUGE_PAGE_SIZE_2MIB == 0 && size - offset >= HUGE_PAGE_SIZE_2MIB {
+				self.map_to_2mib(Page::containing_address(virt), Frame::containing_address(phys), flags, allocator);
+				offset += HUGE_PAGE_SIZE_2MIB;
+			} else {
+				self.map_to(Page::containing_address(virt), Frame::containing_address(phys), flags, allocator);
+				offset += PAGE_SIZE;
+			}
+		}
 	}
 
-	//Modify the page tables unmap a Page to a physical frame - this simply zeros the P1 page table entry for now
-	fn unmap<A>(&mut self, page: Page, allocator: &mut A) where A : FrameAllocator {
+	//The P1 entry of a 4 KiB page if its P3, P2 and P1 tables exist
+	pub fn p1_entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+		let memory = self.memory;
+		self.p4_mut().next_table_mut(page.p4_index(), memory)
+			.and_then(|p3| p3.next_table_mut(page.p3_index(), memory))
+			.and_then(|p2| p2.next_table_mut(page.p2_index(), memory))
+			.map(|p1| &mut p1[page.p1_index()])
+	}
+
+	//Maps a Page to the next free physical Frame from the allocator
+	pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) where A : FrameAllocator {
+		let frame = allocator.allocate_frame().expect("out of memory");
+		self.map_to(page, frame, flags, allocator)
+	}
+
+	//Like map, but returns an error instead of panicking when there are no frames left for the page
+	//or its tables. Nothing is left allocated in that case.
+	pub fn try_map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A) -> Result<(), &'static str>
+		where A : FrameAllocator {
+		assert!(self.huge_page_size(page).is_none(), "page is part of a huge page");
+		let frame = try!(allocator.allocate_frame().ok_or("out of memory"));
+		let memory = self.memory;
+		let result = {
+			let p1 = self.p4_mut().try_next_table_create(page.p4_index(), memory, allocator)
+				.and_then(|p3| p3.try_next_table_create(page.p3_index(), memory, allocator))
+				.and_then(|p2| p2.try_next_table_create(page.p2_index(), memory, allocator));
+			match p1 {
+				Some(p1) => {
+					assert!(p1[page.p1_index()].is_unused());
+					p1[page.p1_index()].set(frame, flags | PRESENT);
+					Ok(())
+				},
+				None => Err(frame),
+			}
+		};
+		result.or_else(|frame| {
+			allocator.deallocate_frame(frame);
+			self.free_empty_tables(page, allocator);
+			Err("out of memory")
+		})
+	}
+
+	//Modify the page tables unmap a Page and free the physical frame it pointed to
+	pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) where A : FrameAllocator {
+		let frame = self.unmap_keep_frame(page, allocator);
+		allocator.deallocate_frame(frame);
+	}
+
+	//Modify the page tables unmap a Page - this simply zeros the P1 page table entry for now
+	//and hands back the frame, which the caller still owns. A huge page containing the page is split first.
+	pub fn unmap_keep_frame<A>(&mut self, page: Page, allocator: &mut A) -> Frame where A : FrameAllocator {
 		assert!(self.translate(page.start_address()).is_some());
 
+		let memory = self.memory;
 		let p1 = self.p4_mut()
-			.next_table_mut(page.p4_index())
-			.and_then(|p3| p3.next_table_mut(page.p3_index()))
-			.and_then(|p2| p2.next_table_mut(page.p2_index()))
-			.expect("mapping code doesn't support huge pages");
+			.next_table_create(page.p4_index(), memory, allocator)
+			.next_table_create(page.p3_index(), memory, allocator)
+			.next_table_create(page.p2_index(), memory, allocator);
 
 		let frame = p1[page.p1_index()].pointed_frame().unwrap();
 		p1[page.p1_index()].set_unused();
-		unsafe { flush_tlb(page.start_address()); }
-		allocator.deallocate_frame(frame);
-		//TODO: deallocate P2, P3 pages if empty?
+		memory.flush(page.start_address());
+		self.free_empty_tables(page, allocator);
+		frame
+	}
+
+	//Gives the P1, P2 and P3 tables on the way to page back to the allocator once they map nothing
+	fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A) where A : FrameAllocator {
+		let memory = self.memory;
+		let p4 = self.p4_mut();
+		if let Some(p3) = p4.next_table_mut(page.p4_index(), memory) {
+			if let Some(p2) = p3.next_table_mut(page.p3_index(), memory) {
+				p2.free_next_table_if_empty(page.p2_index(), memory, allocator);
+			}
+			p3.free_next_table_if_empty(page.p3_index(), memory, allocator);
+		}
+		//kernel half P3 tables are shared between address spaces
+		if page.p4_index() < KERNEL_P4_START {
+			p4.free_next_table_if_empty(page.p4_index(), memory, allocator);
+		}
+	}
+
+	//Unmaps a whole 2 MiB or 1 GiB page starting at page and returns its first frame. The frames are
+	//not given back to the allocator as huge pages normally map memory the allocator doesn't own.
+	pub fn unmap_huge<A>(&mut self, page: Page, allocator: &mut A) -> Frame where A : FrameAllocator {
+		let size = self.huge_page_size(page).expect("page is not part of a huge page");
+		assert!(page.start_address() % size == 0, "page must be the start of the huge page");
+
+		let memory = self.memory;
+		let frame = {
+			let p3 = self.p4_mut().next_table_mut(page.p4_index(), memory).unwrap();
+			if size == HUGE_PAGE_SIZE_1GIB {
+				let frame = p3[page.p3_index()].pointed_frame().unwrap();
+				p3[page.p3_index()].set_unused();
+				frame
+			} else {
+				let p2 = p3.next_table_mut(page.p3_index(), memory).unwrap();
+				let frame = p2[page.p2_index()].pointed_frame().unwrap();
+				p2[page.p2_index()].set_unused();
+				frame
+			}
+		};
+		memory.flush_all();
+		self.free_empty_tables(page, allocator);
+		frame
 	}
 }
 
+
 /*pub fn test_paging<A>(allocator : &mut A) where A : FrameAllocator {
 	let mut page_table = unsafe { PageTable::new_active() };
 	println!("Some = {:?}", page_table.translate(0));
@@ -191,17 +606,25 @@ impl PageTable {
 }*/
 
 pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) where A : FrameAllocator {
+	//gdt64 in boot.asm lives in the boot section, which isn't mapped any more after the switch
+	assert!(::gdt::loaded_gdt_base() as usize >= KERNEL_OFFSET, "the kernel GDT has to be loaded before remapping");
 	let mut active_table = unsafe { PageTable::new_active() };
 
-	let mut temp_table = TempPageTable::new_page_table(Page::containing_address(0xdeadbeef), &mut active_table, allocator);
-	temp_table.with(&mut active_table, |mapper| {
+	let mut new_table = InactivePageTable::new(allocator);
+	new_table.with(|mapper| {
 		let elf_sections_tag = boot_info.elf_sections_tag().expect("Memory map tag required");
+		let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
 
 		for section in elf_sections_tag.sections() {
 			//section not loaded into memory - no need to map it!
 			if !section.is_allocated() {
 				continue;
 			}
+			//the boot section is only used before long mode so it is left out, which also
+			//keeps low memory unmapped
+			if section.start_address() < KERNEL_OFFSET {
+				continue;
+			}
 			let flags = EntryFlags::from_elf_section_flags(section);
 
 			assert!(section.addr % (PAGE_SIZE as u64) == 0, "sections need to be page aligned");
@@ -209,23 +632,538 @@ pub fn remap_kernel<A>(allocator: &mut A, boot_info: &BootInformation) where A :
                      section.addr,
                      section.size);*/
 
-			let start_frame = Frame::containing_address(section.start_address());
-			let end_frame = Frame::containing_address(section.end_address() - 1);
-			for frame in Frame::range_inclusive(start_frame, end_frame) {
-				mapper.identity_map(frame, flags, allocator);
+			//sections are mapped at the address they were linked at, which is KERNEL_OFFSET above
+			//where they were loaded
+			let start_page = Page::containing_address(section.start_address());
+			let end_page = Page::containing_address(section.end_address() - 1);
+			for page in Page::range_inclusive(start_page, end_page) {
+				let frame = Frame::containing_address(virt_to_kernel_phys(page.start_address()));
+				mapper.map_to(page, frame, flags, allocator);
 			}
 		}
 
 		//map vga buffer
-		let vga_buffer_frame = Frame::containing_address(0xb8000);
-		mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+		let vga_buffer_page = Page::containing_address(::vga_buffer::VGA_BUFFER);
+		let vga_buffer_frame = Frame::containing_address(::vga_buffer::VGA_BUFFER_PHYSICAL);
+		mapper.map_to(vga_buffer_page, vga_buffer_frame, WRITABLE | NO_EXECUTE, allocator);
+
+		//map all of physical memory at PHYSICAL_MEMORY_OFFSET - this is also how the multiboot
+		//information and page table frames are reached from now on
+		let memory_end = memory_map_tag.memory_areas()
+			.map(|area| (area.base_addr + area.length) as usize)
+			.max().unwrap();
+		let memory_size = (memory_end + HUGE_PAGE_SIZE_2MIB - 1) & !(HUGE_PAGE_SIZE_2MIB - 1);
+		mapper.map_range_to(Page::containing_address(PHYSICAL_MEMORY_OFFSET), Frame::containing_address(0),
+			memory_size, WRITABLE | NO_EXECUTE, allocator);
+	});
+	let boot_table = active_table.switch(new_table);
+
+	//leave the guard page below the boot stack unmapped so an overflow page faults
+	active_table.unmap_keep_frame(Page::containing_address(stack_guard_page()), allocator);
+
+	//the boot tables from boot.asm live in the kernel's .bss - unmap them there and free their frames
+	let (tables_start, tables_end) = boot_page_tables();
+	assert!(boot_table.p4_frame().start_address() == virt_to_kernel_phys(tables_start), "unexpected boot P4");
+	for page in Page::range_inclusive(Page::containing_address(tables_start), Page::containing_address(tables_end - 1)) {
+		active_table.unmap(page, allocator);
+	}
 
-		//map multiboot buffer
-		let multiboot_start = Frame::containing_address(boot_info.start_address());
-		let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-		for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-			mapper.identity_map(frame, PRESENT, allocator);
+	//the identity map went with the boot tables - the lower half is now empty so null pointer and
+	//other stray low accesses fault
+	assert!((0..KERNEL_P4_START).all(|index| active_table.p4()[index].is_unused()), "lower half still mapped");
+
+	check_writable_xor_executable(&active_table, boot_info);
+}
+
+//Panics if any page is both writable and executable, naming the kernel section it belongs to
+fn check_writable_xor_executable(active_table: &PageTable, boot_info: &BootInformation) {
+	let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
+	walk_page_table(active_table.p4(), |mapping| {
+		if !mapping.flags.contains(WRITABLE) || mapping.flags.contains(NO_EXECUTE) {
+			return;
+		}
+		let section = elf_sections_tag.sections()
+			.filter(|section| section.is_allocated())
+			.find(|section| section.start_address() < mapping.end && section.end_address() > mapping.start);
+		match section {
+			Some(section) => panic!("W^X: {} is writable and executable - kernel section {:#x}-{:#x} has flags {:?}",
+				mapping, section.start_address(), section.end_address(), section.flags()),
+			None => panic!("W^X: {} is writable and executable", mapping),
 		}
 	});
-	temp_table.make_active();
+}
+
+extern {
+	static stack_guard_page: u8;
+	static boot_page_tables: u8;
+	static boot_page_tables_end: u8;
+}
+
+//Physical address of a kernel image address - the boot section is linked at its physical address
+pub fn virt_to_kernel_phys(address: VirtualAddress) -> PhysicalAddress {
+	if address >= KERNEL_OFFSET {
+		address - KERNEL_OFFSET
+	} else {
+		address
+	}
+}
+
+//Address of the page below the boot stack (see boot.asm)
+pub fn stack_guard_page() -> VirtualAddress {
+	unsafe { &stack_guard_page as *const u8 as VirtualAddress }
+}
+
+//Address range of the page tables set up by boot.asm
+fn boot_page_tables() -> (VirtualAddress, VirtualAddress) {
+	unsafe {
+		(&boot_page_tables as *const u8 as VirtualAddress, &boot_page_tables_end as *const u8 as VirtualAddress)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory::physical::SimulatedMemory;
+
+	//Hands out the frames of a SimulatedMemory and counts how many are in use and how many
+	//owners each of them has
+	struct TestAllocator {
+		next: usize,
+		limit: usize,
+		free: Vec<Frame>,
+		used: usize,
+		shares: Vec<usize>,
+	}
+
+	impl TestAllocator {
+		fn new(memory: &SimulatedMemory) -> TestAllocator {
+			TestAllocator { next: 0, limit: memory.frame_count(), free: Vec::new(), used: 0,
+				shares: vec![0; memory.frame_count()] }
+		}
+	}
+
+	impl FrameAllocator for TestAllocator {
+		fn allocate_frame(&mut self) -> Option<Frame> {
+			let frame = match self.free.pop() {
+				Some(frame) => frame,
+				None if self.next < self.limit => {
+					self.next += 1;
+					Frame { number: self.next - 1 }
+				},
+				None => return None,
+			};
+			self.used += 1;
+			Some(frame)
+		}
+
+		fn deallocate_frame(&mut self, frame: Frame) {
+			assert!(!self.free.contains(&frame), "double free of frame {:?}", frame);
+			if self.shares[frame.number] > 0 {
+				self.shares[frame.number] -= 1;
+				return;
+			}
+			self.used -= 1;
+			self.free.push(frame);
+		}
+
+		fn share_frame(&mut self, frame: &Frame) {
+			self.shares[frame.number] += 1;
+		}
+
+		fn is_shared(&self, frame: &Frame) -> bool {
+			self.shares[frame.number] > 0
+		}
+
+		//frames outside the simulated memory stand in for device memory
+		fn owns_frame(&self, frame: &Frame) -> bool {
+			frame.number < self.shares.len()
+		}
+	}
+
+	fn new_table<'a>(memory: &'a SimulatedMemory, allocator: &mut TestAllocator) -> PageTable<&'a SimulatedMemory> {
+		let p4_frame = allocator.allocate_frame().unwrap();
+		unsafe { PageTable::new(p4_frame, memory) }
+	}
+
+	//frames outside the simulated memory - the tests only ever map them, never access them
+	fn data_frame(address: PhysicalAddress) -> Frame {
+		Frame::containing_address(address)
+	}
+
+	#[test]
+	fn map_to_and_translate() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let page = Page::containing_address(0x4020_3000);
+		assert_eq!(table.translate(0x4020_3000), None);
+		table.map_to(page, data_frame(0x1234_5000), WRITABLE, &mut allocator);
+
+		assert_eq!(table.translate(0x4020_3000), Some(0x1234_5000));
+		assert_eq!(table.translate(0x4020_3abc), Some(0x1234_5abc));
+		assert_eq!(table.translate(0x4020_4000), None);
+		assert_eq!(table.huge_page_size(page), None);
+		//a P3, P2 and P1 table on top of the P4
+		assert_eq!(allocator.used, 4);
+	}
+
+	#[test]
+	fn map_to_kernel_half() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let address = KERNEL_OFFSET + 0x10_1000;
+		table.map_to(Page::containing_address(address), data_frame(0x10_1000), EntryFlags::empty(), &mut allocator);
+		assert_eq!(table.translate(address + 8), Some(0x10_1008));
+		assert_eq!(table.translate(address - PAGE_SIZE), None);
+	}
+
+	#[test]
+	fn unmap_frees_frame_and_tables() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let first = Page::containing_address(0x7000_0000);
+		let second = Page::containing_address(0x7000_1000);
+		table.map_to(first, data_frame(0x20_0000), WRITABLE, &mut allocator);
+		table.map(second, WRITABLE, &mut allocator);
+		assert_eq!(allocator.used, 5);
+
+		//the P1 is still in use by the first page
+		table.unmap(second, &mut allocator);
+		assert_eq!(table.translate(second.start_address()), None);
+		assert_eq!(allocator.used, 4);
+
+		//only the P4 is left once nothing is mapped
+		assert_eq!(table.unmap_keep_frame(first, &mut allocator), data_frame(0x20_0000));
+		assert_eq!(table.translate(first.start_address()), None);
+		assert_eq!(allocator.used, 1);
+		assert!(table.p4().is_empty());
+	}
+
+	#[test]
+	fn try_map_cleans_up_when_out_of_frames() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		//enough for the page and its P3, but not its P2 and P1
+		let page = Page::containing_address(0x7000_0000);
+		allocator.limit = allocator.next + 2;
+		assert!(table.try_map(page, WRITABLE, &mut allocator).is_err());
+		assert_eq!(table.translate(page.start_address()), None);
+		assert_eq!(allocator.used, 1);
+		assert!(table.p4().is_empty());
+
+		allocator.limit = memory.frame_count();
+		assert!(table.try_map(page, WRITABLE, &mut allocator).is_ok());
+		assert!(table.translate(page.start_address()).is_some());
+		assert_eq!(allocator.used, 5);
+	}
+
+	//Maps and unmaps pages that each need fresh P3, P2 and P1 tables and checks that every frame
+	//comes back to the allocator
+	#[test]
+	fn unmap_reclaims_tables() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		for i in 0..64 {
+			let address = 0o_002_000_000_000_0000 + i * (HUGE_PAGE_SIZE_1GIB + HUGE_PAGE_SIZE_2MIB + PAGE_SIZE);
+			let page = Page::containing_address(address);
+			table.map(page, WRITABLE | NO_EXECUTE, &mut allocator);
+			assert_eq!(allocator.used, 5);
+			let frame = table.translate(address).map(Frame::containing_address).unwrap();
+			unsafe { *(memory.frame_address(&frame) as *mut u64) = 0xdeadbeef; }
+
+			table.unmap(page, &mut allocator);
+			assert_eq!(table.translate(address), None);
+			assert_eq!(allocator.used, 1, "unmap leaked frames");
+		}
+	}
+
+	#[test]
+	fn translate_2mib_page() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let page = Page::containing_address(0x4000_0000);
+		table.map_to_2mib(page, data_frame(0x80_0000), WRITABLE, &mut allocator);
+
+		assert_eq!(table.translate(0x4000_0000), Some(0x80_0000));
+		assert_eq!(table.translate(0x401f_fff8), Some(0x9f_fff8));
+		assert_eq!(table.translate(0x4020_0000), None);
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4012_3000)), Some(HUGE_PAGE_SIZE_2MIB));
+
+		assert_eq!(table.unmap_huge(page, &mut allocator), data_frame(0x80_0000));
+		assert_eq!(table.translate(0x4000_0000), None);
+		assert_eq!(allocator.used, 1);
+	}
+
+	#[test]
+	fn translate_1gib_page() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let page = Page::containing_address(PHYSICAL_MEMORY_OFFSET);
+		table.map_to_1gib(page, data_frame(0), WRITABLE | NO_EXECUTE, &mut allocator);
+
+		assert_eq!(table.translate(PHYSICAL_MEMORY_OFFSET + 0x1234_5678), Some(0x1234_5678));
+		assert_eq!(table.translate(PHYSICAL_MEMORY_OFFSET + HUGE_PAGE_SIZE_1GIB), None);
+		assert_eq!(table.huge_page_size(Page::containing_address(PHYSICAL_MEMORY_OFFSET + 0x3fff_f000)),
+			Some(HUGE_PAGE_SIZE_1GIB));
+	}
+
+	#[test]
+	fn map_to_splits_huge_page() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		table.map_to_2mib(Page::containing_address(0x4000_0000), data_frame(0x80_0000), WRITABLE, &mut allocator);
+		table.map_to(Page::containing_address(0x4000_5000), data_frame(0x1_0000_0000), WRITABLE, &mut allocator);
+
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4000_0000)), None);
+		assert_eq!(table.translate(0x4000_5010), Some(0x1_0000_0010));
+		//the rest of the huge page still maps the same memory
+		assert_eq!(table.translate(0x4000_4000), Some(0x80_4000));
+		assert_eq!(table.translate(0x401f_f000), Some(0x9f_f000));
+	}
+
+	#[test]
+	fn split_keeps_user_and_pat() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		//a 1 GiB user page using PAT, which lives in bit 12 of the address
+		let page = Page::containing_address(0x4000_5000);
+		table.p4_mut().next_table_create(page.p4_index(), &memory, &mut allocator)[page.p3_index()]
+			.set(Frame::containing_address(0x8000_0000 | HUGE_PAGE_PAT), PRESENT | WRITABLE | USER_ACCESSIBLE | HUGE_PAGE);
+		assert_eq!(table.translate(0x4020_1234), Some(0x8020_1234));
+
+		table.map_to(page, data_frame(0x1_0000_0000), WRITABLE | USER_ACCESSIBLE, &mut allocator);
+		assert_eq!(table.translate(0x4000_5010), Some(0x1_0000_0010));
+		assert_eq!(table.translate(0x4000_4000), Some(0x8000_4000));
+		assert_eq!(table.translate(0x4020_1234), Some(0x8020_1234));
+
+		let p3 = table.p4().next_table(page.p4_index(), &memory).unwrap();
+		assert!(p3[page.p3_index()].flags().contains(USER_ACCESSIBLE));
+		let p2 = p3.next_table(page.p3_index(), &memory).unwrap();
+		assert!(p2[page.p2_index()].flags().contains(USER_ACCESSIBLE));
+		//the 2 MiB pages that weren't split keep PAT in bit 12
+		assert_eq!(p2[1].pointed_frame(), Some(Frame::containing_address(0x8020_0000 | HUGE_PAGE_PAT)));
+		//in the P1 it moved to bit 7
+		let p1 = p2.next_table(page.p2_index(), &memory).unwrap();
+		assert_eq!(p1[4].flags(), PRESENT | WRITABLE | USER_ACCESSIBLE | HUGE_PAGE);
+		assert_eq!(p1[4].pointed_frame(), Some(data_frame(0x8000_4000)));
+	}
+
+	fn p1_flags(table: &PageTable<&SimulatedMemory>, address: VirtualAddress) -> EntryFlags {
+		let memory = table.memory;
+		let page = Page::containing_address(address);
+		table.p4().next_table(page.p4_index(), memory)
+			.and_then(|p3| p3.next_table(page.p3_index(), memory))
+			.and_then(|p2| p2.next_table(page.p2_index(), memory))
+			.map(|p1| p1[page.p1_index()].flags())
+			.unwrap()
+	}
+
+	#[test]
+	fn share_user_half_is_copy_on_write() {
+		let memory = SimulatedMemory::new(32);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut source = new_table(&memory, &mut allocator);
+
+		let data = Page::containing_address(0x40_0000);
+		let code = Page::containing_address(0x40_1000);
+		let device = Page::containing_address(0x40_2000);
+		source.map(data, WRITABLE | USER_ACCESSIBLE, &mut allocator);
+		source.map(code, USER_ACCESSIBLE, &mut allocator);
+		source.map_to(device, data_frame(0xfee0_0000), WRITABLE | NO_CACHE, &mut allocator);
+		let data_frame_address = source.translate(data.start_address()).unwrap();
+		let code_frame_address = source.translate(code.start_address()).unwrap();
+
+		let mut copy = new_table(&memory, &mut allocator);
+		copy.share_user_half(&source.p4_frame, &mut allocator);
+
+		for table in [&source, &copy].iter() {
+			assert_eq!(table.translate(data.start_address()), Some(data_frame_address));
+			assert_eq!(p1_flags(table, data.start_address()), PRESENT | USER_ACCESSIBLE | COPY_ON_WRITE);
+			assert_eq!(p1_flags(table, code.start_address()), PRESENT | USER_ACCESSIBLE);
+			assert_eq!(p1_flags(table, device.start_address()), PRESENT | WRITABLE | NO_CACHE);
+		}
+		assert_eq!(allocator.shares[data_frame_address / PAGE_SIZE], 1);
+		assert_eq!(allocator.shares[code_frame_address / PAGE_SIZE], 1);
+	}
+
+	#[test]
+	fn cow_fault_copies_until_last_owner() {
+		let memory = SimulatedMemory::new(32);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut source = new_table(&memory, &mut allocator);
+
+		let page = Page::containing_address(0x40_0000);
+		source.map(page, WRITABLE, &mut allocator);
+		let original = Frame::containing_address(source.translate(page.start_address()).unwrap());
+		unsafe { *(memory.frame_address(&original) as *mut u64) = 0xdeadbeef; }
+
+		let mut copy = new_table(&memory, &mut allocator);
+		copy.share_user_half(&source.p4_frame, &mut allocator);
+		assert!(!copy.resolve_cow_fault(Page::containing_address(0x40_1000), kernel_write(), &mut allocator));
+
+		//the copy gets a frame of its own with the same contents
+		let used = allocator.used;
+		assert!(copy.resolve_cow_fault(page, kernel_write(), &mut allocator));
+		let copied = Frame::containing_address(copy.translate(page.start_address()).unwrap());
+		assert!(copied != original);
+		assert_eq!(unsafe { *(memory.frame_address(&copied) as *const u64) }, 0xdeadbeef);
+		assert_eq!(p1_flags(&copy, page.start_address()), PRESENT | WRITABLE);
+		assert_eq!(allocator.used, used + 1);
+		assert!(!allocator.is_shared(&original));
+
+		//the source is the last owner and keeps the frame
+		assert!(source.resolve_cow_fault(page, kernel_write(), &mut allocator));
+		assert_eq!(source.translate(page.start_address()), Some(original.start_address()));
+		assert_eq!(p1_flags(&source, page.start_address()), PRESENT | WRITABLE);
+		assert_eq!(allocator.used, used + 1);
+	}
+
+	//error codes of a write to a present page
+	fn kernel_write() -> PageFaultError {
+		PROTECTION_VIOLATION | CAUSED_BY_WRITE
+	}
+
+	fn user_write() -> PageFaultError {
+		kernel_write() | USER_MODE
+	}
+
+	#[test]
+	fn cow_fault_needs_an_allowed_write() {
+		use memory::fault::INSTRUCTION_FETCH;
+		let memory = SimulatedMemory::new(32);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut source = new_table(&memory, &mut allocator);
+
+		//a kernel-only page in the user half
+		let page = Page::containing_address(0x40_0000);
+		source.map(page, WRITABLE, &mut allocator);
+		let mut copy = new_table(&memory, &mut allocator);
+		copy.share_user_half(&source.p4_frame, &mut allocator);
+		let used = allocator.used;
+
+		assert!(!copy.resolve_cow_fault(page, PROTECTION_VIOLATION, &mut allocator));
+		assert!(!copy.resolve_cow_fault(page, PROTECTION_VIOLATION | INSTRUCTION_FETCH, &mut allocator));
+		assert!(!copy.resolve_cow_fault(page, CAUSED_BY_WRITE, &mut allocator));
+		assert!(!copy.resolve_cow_fault(page, user_write(), &mut allocator));
+		assert_eq!(p1_flags(&copy, page.start_address()), PRESENT | COPY_ON_WRITE);
+		assert_eq!(allocator.used, used);
+
+		assert!(copy.resolve_cow_fault(page, kernel_write(), &mut allocator));
+		assert_eq!(p1_flags(&copy, page.start_address()), PRESENT | WRITABLE);
+	}
+
+	#[test]
+	fn cow_fault_splits_huge_page() {
+		//the second half of the memory is a 2 MiB page of RAM that is never handed out
+		let memory = SimulatedMemory::new(ENTRY_COUNT * 2);
+		let mut allocator = TestAllocator::new(&memory);
+		allocator.limit = ENTRY_COUNT;
+		let mut source = new_table(&memory, &mut allocator);
+
+		let huge_page = Page::containing_address(0x4000_0000);
+		let huge_frame = Frame { number: ENTRY_COUNT };
+		source.map_to_2mib(huge_page, huge_frame.clone(), WRITABLE | USER_ACCESSIBLE, &mut allocator);
+
+		let mut copy = new_table(&memory, &mut allocator);
+		copy.share_user_half(&source.p4_frame, &mut allocator);
+		assert!(allocator.shares[ENTRY_COUNT..].iter().all(|&shares| shares == 1));
+
+		let written = Page::containing_address(0x4000_3000);
+		assert!(copy.resolve_cow_fault(written, user_write(), &mut allocator));
+		assert_eq!(copy.huge_page_size(written), None);
+		assert!(copy.translate(written.start_address()) != Some(huge_frame.start_address() + 0x3000));
+		assert_eq!(p1_flags(&copy, written.start_address()), PRESENT | WRITABLE | USER_ACCESSIBLE);
+		//the rest of the huge page is still shared
+		assert_eq!(copy.translate(0x4000_4000), Some(huge_frame.start_address() + 0x4000));
+		assert_eq!(p1_flags(&copy, 0x4000_4000), PRESENT | USER_ACCESSIBLE | COPY_ON_WRITE);
+		assert_eq!(allocator.shares[ENTRY_COUNT + 3], 0);
+		assert_eq!(allocator.shares[ENTRY_COUNT + 4], 1);
+		assert_eq!(source.huge_page_size(huge_page), Some(HUGE_PAGE_SIZE_2MIB));
+	}
+
+	#[test]
+	fn share_user_half_keeps_pat() {
+		let memory = SimulatedMemory::new(ENTRY_COUNT * 2);
+		let mut allocator = TestAllocator::new(&memory);
+		allocator.limit = ENTRY_COUNT;
+		let mut source = new_table(&memory, &mut allocator);
+
+		//a 2 MiB page of RAM using PAT, which lives in bit 12 of the address
+		let huge_page = Page::containing_address(0x4000_0000);
+		let huge_frame = Frame::containing_address(ENTRY_COUNT * PAGE_SIZE | HUGE_PAGE_PAT);
+		source.map_to_2mib(huge_page, huge_frame.clone(), WRITABLE | USER_ACCESSIBLE, &mut allocator);
+
+		let mut copy = new_table(&memory, &mut allocator);
+		copy.share_user_half(&source.p4_frame, &mut allocator);
+		for table in [&source, &copy].iter() {
+			let p2 = table.p4().next_table(huge_page.p4_index(), &memory)
+				.and_then(|p3| p3.next_table(huge_page.p3_index(), &memory))
+				.unwrap();
+			assert_eq!(p2[huge_page.p2_index()].pointed_frame(), Some(huge_frame.clone()));
+			assert_eq!(p2[huge_page.p2_index()].flags(), PRESENT | USER_ACCESSIBLE | HUGE_PAGE | COPY_ON_WRITE);
+		}
+		assert!(allocator.shares[ENTRY_COUNT..].iter().all(|&shares| shares == 1));
+	}
+
+	#[test]
+	fn map_range_to_uses_huge_pages() {
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+
+		let start = 0x4000_0000 - PAGE_SIZE;
+		table.map_range_to(Page::containing_address(start), data_frame(0x20_0000 - PAGE_SIZE),
+			HUGE_PAGE_SIZE_2MIB * 2 + PAGE_SIZE, WRITABLE, &mut allocator);
+
+		assert_eq!(table.huge_page_size(Page::containing_address(start)), None);
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4000_0000)), Some(HUGE_PAGE_SIZE_2MIB));
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4020_0000)), Some(HUGE_PAGE_SIZE_2MIB));
+		assert_eq!(table.translate(start), Some(0x1f_f000));
+		assert_eq!(table.translate(0x403f_ffff), Some(0x5f_ffff));
+		assert_eq!(table.translate(0x4040_0000), None);
+	}
+
+	#[test]
+	fn map_range_to_uses_1gib_pages() {
+		let memory = SimulatedMemory::new(16);
+		let start = 0x4000_0000 - HUGE_PAGE_SIZE_2MIB;
+		let size = HUGE_PAGE_SIZE_1GIB + HUGE_PAGE_SIZE_2MIB * 2;
+
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+		table.use_1gib_pages(true);
+		table.map_range_to(Page::containing_address(start), data_frame(start), size, WRITABLE, &mut allocator);
+
+		assert_eq!(table.huge_page_size(Page::containing_address(start)), Some(HUGE_PAGE_SIZE_2MIB));
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4000_0000)), Some(HUGE_PAGE_SIZE_1GIB));
+		assert_eq!(table.huge_page_size(Page::containing_address(0x7fff_f000)), Some(HUGE_PAGE_SIZE_1GIB));
+		assert_eq!(table.huge_page_size(Page::containing_address(0x8000_0000)), Some(HUGE_PAGE_SIZE_2MIB));
+		assert_eq!(table.translate(0x7654_3210), Some(0x7654_3210));
+		assert_eq!(table.translate(start + size), None);
+
+		//without them the same range takes 2 MiB pages only
+		let memory = SimulatedMemory::new(16);
+		let mut allocator = TestAllocator::new(&memory);
+		let mut table = new_table(&memory, &mut allocator);
+		table.map_range_to(Page::containing_address(start), data_frame(start), size, WRITABLE, &mut allocator);
+		assert_eq!(table.huge_page_size(Page::containing_address(0x4000_0000)), Some(HUGE_PAGE_SIZE_2MIB));
+		assert_eq!(table.translate(0x7654_3210), Some(0x7654_3210));
+	}
 }
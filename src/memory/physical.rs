@@ -0,0 +1,68 @@
+use memory::{Frame, phys_to_virt};
+use memory::page::VirtualAddress;
+use x86::{flush_tlb, flush_tlb_all};
+
+//How the paging code reaches the page table frames it walks. The kernel goes through the physical
+//memory mapping - host-side tests back frames with ordinary memory instead.
+pub trait PhysicalMemory : Copy {
+	//Address at which the contents of frame can be read and written
+	fn frame_address(&self, frame: &Frame) -> VirtualAddress;
+	//Called after the mapping of address was changed or removed
+	fn flush(&self, address: VirtualAddress);
+	fn flush_all(&self);
+}
+
+//Physical memory as seen through the mapping at PHYSICAL_MEMORY_OFFSET
+#[derive(Clone, Copy)]
+pub struct PhysicalMapping;
+
+impl PhysicalMemory for PhysicalMapping {
+	fn frame_address(&self, frame: &Frame) -> VirtualAddress {
+		phys_to_virt(frame.start_address())
+	}
+
+	fn flush(&self, address: VirtualAddress) {
+		unsafe { flush_tlb(address); }
+	}
+
+	fn flush_all(&self) {
+		unsafe { flush_tlb_all(); }
+	}
+}
+
+//Frames backed by ordinary memory for the host-side tests. Frame n is the nth element of frames -
+//the tests map frames past the end as well, as long as nothing reads or writes them.
+#[cfg(test)]
+pub struct SimulatedMemory {
+	frames: Vec<[u8; ::memory::PAGE_SIZE]>,
+	base: usize,
+}
+
+#[cfg(test)]
+impl SimulatedMemory {
+	pub fn new(frame_count: usize) -> SimulatedMemory {
+		let mut frames = vec![[0; ::memory::PAGE_SIZE]; frame_count];
+		let base = frames.as_mut_ptr() as usize;
+		assert!(base % 8 == 0, "page table entries must be aligned");
+		SimulatedMemory {
+			frames: frames,
+			base: base,
+		}
+	}
+
+	pub fn frame_count(&self) -> usize {
+		self.frames.len()
+	}
+}
+
+#[cfg(test)]
+impl<'a> PhysicalMemory for &'a SimulatedMemory {
+	fn frame_address(&self, frame: &Frame) -> VirtualAddress {
+		assert!(frame.number < self.frames.len(), "frame {:?} is outside the simulated memory", frame);
+		self.base + frame.number * ::memory::PAGE_SIZE
+	}
+
+	fn flush(&self, _address: VirtualAddress) {}
+
+	fn flush_all(&self) {}
+}
@@ -0,0 +1,142 @@
+use memory::{Frame, FrameAllocator, MEMORY, PAGE_SIZE, phys_to_virt};
+use memory::page::{Page, VirtualAddress};
+use memory::entry::*;
+use memory::fault::*;
+use sync::IrqMutex;
+
+//Virtual memory that is reserved up front but only backed by frames once it is touched -
+//the page fault handler maps a zeroed frame the first time each page is accessed
+
+const MAX_REGIONS: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+	pub start: VirtualAddress,
+	pub end: VirtualAddress,
+	pub flags: EntryFlags,
+}
+
+impl Region {
+	pub fn contains(&self, address: VirtualAddress) -> bool {
+		address >= self.start && address < self.end
+	}
+
+	fn overlaps(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+		start < self.end && end > self.start
+	}
+}
+
+pub struct RegionList {
+	regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl RegionList {
+	pub const fn new() -> RegionList {
+		RegionList {
+			regions: [None; MAX_REGIONS],
+		}
+	}
+
+	pub fn find(&self, address: VirtualAddress) -> Option<Region> {
+		self.regions.iter()
+			.filter_map(|region| *region)
+			.find(|region| region.contains(address))
+	}
+}
+
+//always taken before MEMORY. The page fault handler only ever tries to take either of them, as
+//a fault can happen while the faulting code holds them.
+pub static REGIONS: IrqMutex<RegionList> = IrqMutex::new(RegionList::new());
+
+//Reserves size bytes at start (both page aligned) to be mapped with flags on first access
+pub fn reserve_region(start: VirtualAddress, size: usize, flags: EntryFlags) -> Result<(), &'static str> {
+	if start % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || size == 0 {
+		return Err("Region must be page aligned");
+	}
+	let end = start + size;
+
+	let mut regions = REGIONS.lock();
+	if regions.regions.iter().filter_map(|region| *region).any(|region| region.overlaps(start, end)) {
+		return Err("Region overlaps an existing region");
+	}
+	match regions.regions.iter_mut().find(|region| region.is_none()) {
+		Some(slot) => {
+			*slot = Some(Region { start: start, end: end, flags: flags | PRESENT });
+			Ok(())
+		},
+		None => Err("Too many regions"),
+	}
+}
+
+//Removes the region starting at start and frees every page of it that was touched
+pub fn release_region(start: VirtualAddress) -> Result<(), &'static str> {
+	let mut regions = REGIONS.lock();
+	let slot = try!(regions.regions.iter_mut()
+		.find(|region| region.map_or(false, |region| region.start == start))
+		.ok_or("No region starts at this address"));
+	let region = slot.take().unwrap();
+
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+	let start_page = Page::containing_address(region.start);
+	let end_page = Page::containing_address(region.end - 1);
+	for page in Page::range_inclusive(start_page, end_page) {
+		if memory.active_table.translate(page.start_address()).is_some() {
+			memory.active_table.unmap(page, &mut memory.frame_allocator);
+		}
+	}
+	Ok(())
+}
+
+//Maps a zeroed frame if the address is in a region, not yet mapped and the access is one the
+//region's flags allow. Returns false if the fault isn't a demand paging fault so the caller can report it.
+pub fn handle_demand_fault(address: VirtualAddress, error: PageFaultError) -> bool {
+	let regions = match REGIONS.try_lock() {
+		Some(regions) => regions,
+		None => return false, // faulted while the region list was being changed
+	};
+	let region = match regions.find(address) {
+		Some(region) => region,
+		None => return false,
+	};
+	if !access_allowed(&region, error) {
+		return false;
+	}
+
+	let mut lock = match MEMORY.try_lock() {
+		Some(lock) => lock,
+		None => return false, // faulted while the page tables were being changed
+	};
+	let memory = match lock.as_mut() {
+		Some(memory) => memory,
+		None => return false,
+	};
+
+	//already mapped - so this is an access the region's flags don't allow
+	let page = Page::containing_address(address);
+	if memory.active_table.translate(page.start_address()).is_some() {
+		return false;
+	}
+
+	let frame = match memory.frame_allocator.allocate_frame() {
+		Some(frame) => frame,
+		None => return false,
+	};
+	zero_frame(&frame);
+	memory.active_table.map_to(page, frame, region.flags, &mut memory.frame_allocator);
+	true
+}
+
+fn access_allowed(region: &Region, error: PageFaultError) -> bool {
+	!error.contains(PROTECTION_VIOLATION)
+		&& !(error.contains(CAUSED_BY_WRITE) && !region.flags.contains(WRITABLE))
+		&& !(error.contains(USER_MODE) && !region.flags.contains(USER_ACCESSIBLE))
+		&& !(error.contains(INSTRUCTION_FETCH) && region.flags.contains(NO_EXECUTE))
+}
+
+fn zero_frame(frame: &Frame) {
+	let frame_ptr = phys_to_virt(frame.start_address()) as *mut u64;
+	for i in 0..(PAGE_SIZE / 8) {
+		unsafe { *frame_ptr.offset(i as isize) = 0; }
+	}
+}
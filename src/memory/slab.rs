@@ -0,0 +1,337 @@
+use memory::{Frame, FrameAllocator, MEMORY, PAGE_SIZE, PHYSICAL_MEMORY_OFFSET, phys_to_virt};
+use core::alloc::Layout;
+use core::{mem, ptr};
+use sync::IrqMutex;
+
+//Object caches for fixed size kernel objects. Every slab is a single frame reached through the
+//physical memory mapping, with a small header followed by the objects, so the slab an object
+//belongs to is found by rounding its address down to the frame. The header names the cache that owns
+//the slab, so frees are checked without walking the cache's slabs - a cache must not move once it
+//has slabs, which the statics below never do.
+
+const OBJECT_ALIGN: usize = 16;
+
+struct FreeObject {
+	next: *mut FreeObject
+}
+
+struct Slab {
+	cache: *const SlabCache,
+	prev: *mut Slab,
+	next: *mut Slab,
+	free_list: *mut FreeObject,
+	in_use: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlabStats {
+	pub object_size: usize,
+	pub objects_in_use: usize,
+	pub objects_total: usize,
+	pub slabs: usize,
+	pub allocations: usize,
+	pub frees: usize,
+}
+
+pub struct SlabCache {
+	name: &'static str,
+	object_size: usize,
+	slabs: *mut Slab,
+	stats: SlabStats,
+}
+
+// the slab list only points at frames owned by this cache
+unsafe impl Send for SlabCache {}
+
+fn align_up(value: usize, align: usize) -> usize {
+	(value + align - 1) & !(align - 1)
+}
+
+fn header_size() -> usize {
+	align_up(mem::size_of::<Slab>(), OBJECT_ALIGN)
+}
+
+impl SlabCache {
+	pub const fn new(name: &'static str, object_size: usize) -> SlabCache {
+		SlabCache {
+			name: name,
+			object_size: object_size,
+			slabs: 0 as *mut Slab,
+			stats: SlabStats {
+				object_size: object_size,
+				objects_in_use: 0,
+				objects_total: 0,
+				slabs: 0,
+				allocations: 0,
+				frees: 0,
+			},
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		self.name
+	}
+
+	pub fn stats(&self) -> SlabStats {
+		self.stats
+	}
+
+	//objects are at least big enough to hold the free list link and keep OBJECT_ALIGN alignment
+	fn slot_size(&self) -> usize {
+		align_up(if self.object_size > OBJECT_ALIGN { self.object_size } else { OBJECT_ALIGN }, OBJECT_ALIGN)
+	}
+
+	fn objects_per_slab(&self) -> usize {
+		(PAGE_SIZE - header_size()) / self.slot_size()
+	}
+
+	//Takes a new frame from the frame allocator and threads all of its objects onto a free list
+	fn grow(&mut self) -> bool {
+		assert!(self.objects_per_slab() > 0, "slab objects must fit in a page");
+		let frame = {
+			let mut lock = MEMORY.lock();
+			let memory = lock.as_mut().expect("memory not initialised");
+			match memory.frame_allocator.allocate_frame() {
+				Some(frame) => frame,
+				None => return false,
+			}
+		};
+
+		let base = phys_to_virt(frame.start_address());
+		let slot_size = self.slot_size();
+		unsafe {
+			let slab = base as *mut Slab;
+			let mut free_list: *mut FreeObject = ptr::null_mut();
+			for i in (0..self.objects_per_slab()).rev() {
+				let object = (base + header_size() + i * slot_size) as *mut FreeObject;
+				(*object).next = free_list;
+				free_list = object;
+			}
+			ptr::write(slab, Slab { cache: self, prev: ptr::null_mut(), next: self.slabs, free_list: free_list, in_use: 0 });
+			if !self.slabs.is_null() {
+				(*self.slabs).prev = slab;
+			}
+			self.slabs = slab;
+		}
+		self.stats.slabs += 1;
+		self.stats.objects_total += self.objects_per_slab();
+		true
+	}
+
+	pub fn allocate(&mut self) -> Option<*mut u8> {
+		unsafe {
+			let mut slab = self.slabs;
+			while !slab.is_null() && (*slab).free_list.is_null() {
+				slab = (*slab).next;
+			}
+			if slab.is_null() {
+				if !self.grow() {
+					return None;
+				}
+				slab = self.slabs;
+			}
+
+			let object = (*slab).free_list;
+			(*slab).free_list = (*object).next;
+			(*slab).in_use += 1;
+			self.stats.objects_in_use += 1;
+			self.stats.allocations += 1;
+			Some(object as *mut u8)
+		}
+	}
+
+	//Returns an object to its slab - a slab that becomes empty goes back to the frame allocator
+	//unless it is the only one left
+	pub unsafe fn free(&mut self, object: *mut u8) {
+		let slab = (object as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+		assert!((*slab).cache == self as *const SlabCache, "{}: {:p} is not in one of this cache's slabs", self.name, object);
+		let offset = object as usize - slab as usize;
+		assert!(offset >= header_size() && (offset - header_size()) % self.slot_size() == 0,
+			"{}: {:p} is not the start of an object", self.name, object);
+
+		let free_object = object as *mut FreeObject;
+		(*free_object).next = (*slab).free_list;
+		(*slab).free_list = free_object;
+		(*slab).in_use -= 1;
+		self.stats.objects_in_use -= 1;
+		self.stats.frees += 1;
+
+		if (*slab).in_use == 0 && self.stats.slabs > 1 {
+			self.release_slab(slab);
+		}
+	}
+
+	unsafe fn release_slab(&mut self, slab: *mut Slab) {
+		if (*slab).prev.is_null() {
+			self.slabs = (*slab).next;
+		} else {
+			(*(*slab).prev).next = (*slab).next;
+		}
+		if !(*slab).next.is_null() {
+			(*(*slab).next).prev = (*slab).prev;
+		}
+		//a stale pointer into the frame must not pass the check in free
+		(*slab).cache = ptr::null();
+		self.stats.slabs -= 1;
+		self.stats.objects_total -= self.objects_per_slab();
+
+		let frame = Frame::containing_address(slab as usize - PHYSICAL_MEMORY_OFFSET);
+		let mut lock = MEMORY.lock();
+		lock.as_mut().expect("memory not initialised").frame_allocator.deallocate_frame(frame);
+	}
+}
+
+//Whole frame objects, like the buffers of the block cache, don't fit into a slab next to its header.
+//Their cache hands out frames and keeps up to `keep` freed frames on a list threaded through the frames.
+pub struct FrameCache {
+	name: &'static str,
+	free_list: *mut FreeObject,
+	free_count: usize,
+	keep: usize,
+	stats: SlabStats,
+}
+
+// the free list only points at frames owned by this cache
+unsafe impl Send for FrameCache {}
+
+impl FrameCache {
+	pub const fn new(name: &'static str, keep: usize) -> FrameCache {
+		FrameCache {
+			name: name,
+			free_list: 0 as *mut FreeObject,
+			free_count: 0,
+			keep: keep,
+			stats: SlabStats {
+				object_size: PAGE_SIZE,
+				objects_in_use: 0,
+				objects_total: 0,
+				slabs: 0,
+				allocations: 0,
+				frees: 0,
+			},
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		self.name
+	}
+
+	pub fn stats(&self) -> SlabStats {
+		self.stats
+	}
+
+	pub fn allocate(&mut self) -> Option<*mut u8> {
+		let object = if self.free_list.is_null() {
+			let frame = {
+				let mut lock = MEMORY.lock();
+				let memory = lock.as_mut().expect("memory not initialised");
+				match memory.frame_allocator.allocate_frame() {
+					Some(frame) => frame,
+					None => return None,
+				}
+			};
+			//every frame counts as a slab of one object
+			self.stats.slabs += 1;
+			self.stats.objects_total += 1;
+			phys_to_virt(frame.start_address()) as *mut FreeObject
+		} else {
+			let object = self.free_list;
+			self.free_list = unsafe { (*object).next };
+			self.free_count -= 1;
+			object
+		};
+		self.stats.objects_in_use += 1;
+		self.stats.allocations += 1;
+		Some(object as *mut u8)
+	}
+
+	pub unsafe fn free(&mut self, object: *mut u8) {
+		let address = object as usize;
+		assert!(address >= PHYSICAL_MEMORY_OFFSET && address % PAGE_SIZE == 0,
+			"{}: {:p} is not an object of this cache", self.name, object);
+		self.stats.objects_in_use -= 1;
+		self.stats.frees += 1;
+
+		if self.free_count < self.keep {
+			let free_object = object as *mut FreeObject;
+			(*free_object).next = self.free_list;
+			self.free_list = free_object;
+			self.free_count += 1;
+		} else {
+			self.stats.slabs -= 1;
+			self.stats.objects_total -= 1;
+			let frame = Frame::containing_address(address - PHYSICAL_MEMORY_OFFSET);
+			let mut lock = MEMORY.lock();
+			lock.as_mut().expect("memory not initialised").frame_allocator.deallocate_frame(frame);
+		}
+	}
+}
+
+pub const BLOCK_BUFFER_SIZE: usize = PAGE_SIZE;
+pub type BlockBuffer = [u8; BLOCK_BUFFER_SIZE];
+
+pub static BLOCK_BUFFERS: IrqMutex<FrameCache> = IrqMutex::new(FrameCache::new("block-buffers", 64));
+
+//A buffer for one block of the block cache - its contents are whatever the last user left in it
+pub fn alloc_block_buffer() -> Option<&'static mut BlockBuffer> {
+	BLOCK_BUFFERS.lock().allocate().map(|buffer| unsafe { &mut *(buffer as *mut BlockBuffer) })
+}
+
+pub fn free_block_buffer(buffer: &'static mut BlockBuffer) {
+	unsafe { BLOCK_BUFFERS.lock().free(buffer as *mut BlockBuffer as *mut u8); }
+}
+
+//General purpose caches for objects up to 1 KiB - the kernel heap takes every small allocation from
+//them, so they are locked like the heap. Only one 2 KiB object would fit next to a slab header, so
+//those come from the heap itself.
+const SIZE_CLASSES: usize = 6;
+
+pub static SLAB_CACHES: IrqMutex<[SlabCache; SIZE_CLASSES]> = IrqMutex::new([
+	SlabCache::new("slab-32", 32),
+	SlabCache::new("slab-64", 64),
+	SlabCache::new("slab-128", 128),
+	SlabCache::new("slab-256", 256),
+	SlabCache::new("slab-512", 512),
+	SlabCache::new("slab-1024", 1024),
+]);
+
+fn size_class(size: usize) -> Option<usize> {
+	let mut class_size = 32;
+	for class in 0..SIZE_CLASSES {
+		if size <= class_size {
+			return Some(class);
+		}
+		class_size *= 2;
+	}
+	None
+}
+
+//Can an allocation with this layout come from one of the general purpose caches?
+pub fn fits_slab_cache(layout: &Layout) -> bool {
+	layout.align() <= OBJECT_ALIGN && size_class(layout.size()).is_some()
+}
+
+//Allocates an object of at least size bytes from the smallest cache it fits in
+pub fn slab_alloc(size: usize) -> Option<*mut u8> {
+	size_class(size).and_then(|class| SLAB_CACHES.lock()[class].allocate())
+}
+
+//Frees an object from slab_alloc - size must be the size it was allocated with
+pub unsafe fn slab_free(object: *mut u8, size: usize) {
+	let class = size_class(size).expect("size too big for a slab cache");
+	SLAB_CACHES.lock()[class].free(object);
+}
+
+pub fn print_slab_stats() {
+	let caches = SLAB_CACHES.lock();
+	for cache in caches.iter() {
+		print_cache_stats(cache.name(), cache.stats());
+	}
+	let block_buffers = BLOCK_BUFFERS.lock();
+	print_cache_stats(block_buffers.name(), block_buffers.stats());
+}
+
+pub fn print_cache_stats(name: &str, stats: SlabStats) {
+	println!("{}: {} of {} objects in use, {} slabs, {} allocs, {} frees",
+		name, stats.objects_in_use, stats.objects_total, stats.slabs, stats.allocations, stats.frees);
+}
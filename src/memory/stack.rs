@@ -0,0 +1,41 @@
+use memory::{MEMORY, PAGE_SIZE};
+use memory::page::{Page, VirtualAddress};
+use memory::pagetable::HUGE_PAGE_SIZE_1GIB;
+use memory::entry::*;
+
+//Kernel stacks for the other CPUs live in their own window of the kernel half. Every stack sits
+//above an unmapped guard page so an overflow faults instead of running into the next one.
+pub const STACKS_START: VirtualAddress = 0o_177777_621_000_000_000_0000; // P4 entry 401
+pub const STACKS_SIZE: usize = HUGE_PAGE_SIZE_1GIB * 512;
+pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE * 8;
+const STACK_SLOT_SIZE: usize = KERNEL_STACK_SIZE + PAGE_SIZE;
+
+#[derive(Debug)]
+pub struct Stack {
+	pub top: VirtualAddress,
+	pub bottom: VirtualAddress,
+}
+
+//Maps a fresh stack of KERNEL_STACK_SIZE bytes. Stacks are never freed.
+pub fn alloc_stack() -> Option<Stack> {
+	let mut lock = MEMORY.lock();
+	let memory = lock.as_mut().expect("memory not initialised");
+
+	let slot = memory.next_stack_address;
+	if slot + STACK_SLOT_SIZE > STACKS_START + STACKS_SIZE {
+		return None;
+	}
+	memory.next_stack_address += STACK_SLOT_SIZE;
+
+	let bottom = slot + PAGE_SIZE;
+	for page in Page::range_inclusive(Page::containing_address(bottom), Page::containing_address(bottom + KERNEL_STACK_SIZE - 1)) {
+		memory.active_table.map(page, WRITABLE | NO_EXECUTE, &mut memory.frame_allocator);
+	}
+	Some(Stack { top: bottom + KERNEL_STACK_SIZE, bottom: bottom })
+}
+
+//Is the address inside the guard page of one of the stacks from alloc_stack?
+pub fn is_guard_page(address: VirtualAddress) -> bool {
+	address >= STACKS_START && address < STACKS_START + STACKS_SIZE &&
+		(address - STACKS_START) % STACK_SLOT_SIZE < PAGE_SIZE
+}
@@ -1,96 +1,160 @@
-use memory::entry::*;
-use memory::pagetable::ENTRY_COUNT;
-use core::ops::{Index, IndexMut};
-use core::marker::PhantomData;
-use memory::FrameAllocator;
-
-pub trait TableLevel {}
-pub enum Level4 {}
-pub enum Level3 {}
-pub enum Level2 {}
-pub enum Level1 {}
-
-impl TableLevel for Level4 {}
-impl TableLevel for Level3 {}
-impl TableLevel for Level2 {}
-impl TableLevel for Level1 {}
-
-pub trait HierarchicalLevel : TableLevel {
-	type NextLevel: TableLevel;
-}
-impl HierarchicalLevel for Level4 {
-	type NextLevel = Level3;
-}
-impl HierarchicalLevel for Level3 {
-	type NextLevel = Level2;
-}
-impl HierarchicalLevel for Level2 {
-	type NextLevel = Level1;
-}
-
-pub struct Table<L: TableLevel> {
-	entries: [Entry; ENTRY_COUNT],
-	level: PhantomData<L>,
-}
-
-impl<L> Table<L> where L: TableLevel {
-	pub fn zero(&mut self) {
-		for entry in self.entries.iter_mut() {
-			entry.set_unused();
-		}
-	}
-}
-
-impl<L> Table<L> where L: HierarchicalLevel {
-	//returns the full address (u64) of the next page table down the page table hierarchy (e.g. p4 -> p3)
-	fn next_table_address(&self, index: usize) -> Option<usize> {
-		let entry_flags = self[index].flags();
-		if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
-			let table_address = self as *const _ as usize;
-			Some((table_address << 9) | (index << 12)) // shift along the table address bits and add in the new index at the end
-			//P4	0o177777_777_777_777_777_0000	–
-			//P3	0o177777_777_777_777_XXX_0000	XXX is the P4 index
-			//P2	0o177777_777_777_XXX_YYY_0000	like above, and YYY is the P3 index
-			//P1	0o177777_777_XXX_YYY_ZZZ_0000	like above, and ZZZ is the P2 index
-		} else {
-			None
-		}
-	}
-
-	pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
-		self.next_table_address(index)
-			.map(|address| unsafe { &*(address as *const _) })
-	}
-
-	pub fn next_table_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
-		self.next_table_address(index)
-			.map(|address| unsafe { &mut *(address as *mut _) })
-	}
-
-	pub fn next_table_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::NextLevel>
-		where A : FrameAllocator {
-		//do we have a page table entry already available for this index?
-		if self.next_table(index).is_none() {
-			assert!(!self.entries[index].flags().contains(HUGE_PAGE), "huge pages unsupported");
-			let frame = allocator.allocate_frame().expect("no frames available");
-			self.entries[index].set(frame, PRESENT | WRITABLE);
-			self.next_table_mut(index).unwrap().zero();
-		}
-		self.next_table_mut(index).unwrap()
-	}
-}
-
-impl<L> Index<usize> for Table<L> where L : TableLevel {
-	type Output = Entry;
-
-	fn index(&self, index: usize) -> &Entry {
-		&self.entries[index]
-	}
-}
-
-impl<L> IndexMut<usize> for Table<L> where L : TableLevel {
-	fn index_mut(&mut self, index: usize) -> &mut Entry {
-		&mut self.entries[index]
-	}
-}
-
+use memory::entry::*;
+use memory::pagetable::ENTRY_COUNT;
+use core::ops::{Index, IndexMut};
+use core::marker::PhantomData;
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::physical::PhysicalMemory;
+
+pub trait TableLevel {}
+pub enum Level4 {}
+pub enum Level3 {}
+pub enum Level2 {}
+pub enum Level1 {}
+
+impl TableLevel for Level4 {}
+impl TableLevel for Level3 {}
+impl TableLevel for Level2 {}
+impl TableLevel for Level1 {}
+
+pub trait HierarchicalLevel : TableLevel {
+	type NextLevel: TableLevel;
+	//number of 4 KiB frames covered by a single entry in a table of this level
+	fn entry_frames() -> usize;
+}
+impl HierarchicalLevel for Level4 {
+	type NextLevel = Level3;
+	fn entry_frames() -> usize { ENTRY_COUNT * ENTRY_COUNT * ENTRY_COUNT }
+}
+impl HierarchicalLevel for Level3 {
+	type NextLevel = Level2;
+	fn entry_frames() -> usize { ENTRY_COUNT * ENTRY_COUNT }
+}
+impl HierarchicalLevel for Level2 {
+	type NextLevel = Level1;
+	fn entry_frames() -> usize { ENTRY_COUNT }
+}
+
+pub struct Table<L: TableLevel> {
+	entries: [Entry; ENTRY_COUNT],
+	level: PhantomData<L>,
+}
+
+impl<L> Table<L> where L: TableLevel {
+	pub fn zero(&mut self) {
+		for entry in self.entries.iter_mut() {
+			entry.set_unused();
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.iter().all(|entry| entry.is_unused())
+	}
+}
+
+impl<L> Table<L> where L: HierarchicalLevel {
+	//the frame of the next page table down the hierarchy (e.g. p4 -> p3), if the entry points at one
+	fn next_table_frame(&self, index: usize) -> Option<Frame> {
+		let entry_flags = self[index].flags();
+		if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+			self[index].pointed_frame()
+		} else {
+			None
+		}
+	}
+
+	pub fn next_table<M>(&self, index: usize, memory: M) -> Option<&Table<L::NextLevel>> where M : PhysicalMemory {
+		self.next_table_frame(index)
+			.map(|frame| unsafe { &*(memory.frame_address(&frame) as *const _) })
+	}
+
+	pub fn next_table_mut<M>(&mut self, index: usize, memory: M) -> Option<&mut Table<L::NextLevel>>
+		where M : PhysicalMemory {
+		self.next_table_frame(index)
+			.map(|frame| unsafe { &mut *(memory.frame_address(&frame) as *mut _) })
+	}
+
+	pub fn next_table_create<M, A>(&mut self, index: usize, memory: M, allocator: &mut A) -> &mut Table<L::NextLevel>
+		where M : PhysicalMemory, A : FrameAllocator {
+		self.try_next_table_create(index, memory, allocator).expect("no frames available")
+	}
+
+	//Like next_table_create, but returns None if there is no frame left for the new table
+	pub fn try_next_table_create<M, A>(&mut self, index: usize, memory: M, allocator: &mut A) -> Option<&mut Table<L::NextLevel>>
+		where M : PhysicalMemory, A : FrameAllocator {
+		//do we have a page table entry already available for this index?
+		if self.next_table(index, memory).is_none() {
+			let frame = match allocator.allocate_frame() {
+				Some(frame) => frame,
+				None => return None,
+			};
+			let huge_flags = self.entries[index].flags();
+			if huge_flags.contains(PRESENT | HUGE_PAGE) {
+				self.split_huge_page(index, frame, memory);
+			} else {
+				self.entries[index].set(frame, PRESENT | WRITABLE);
+				self.next_table_mut(index, memory).unwrap().zero();
+			}
+		}
+		self.next_table_mut(index, memory)
+	}
+
+	//Frees the next table down the hierarchy if none of its entries are in use any more.
+	//The caller has already flushed the TLB for the entry that emptied it, which also drops any
+	//cached pointer to the table.
+	pub fn free_next_table_if_empty<M, A>(&mut self, index: usize, memory: M, allocator: &mut A) -> bool
+		where M : PhysicalMemory, A : FrameAllocator {
+		let empty = self.next_table(index, memory).map_or(false, |table| table.is_empty());
+		if empty {
+			let frame = self.entries[index].pointed_frame().unwrap();
+			self.entries[index].set_unused();
+			allocator.deallocate_frame(frame);
+		}
+		empty
+	}
+
+	//Replaces a huge page entry with a table of smaller pages that map the same physical memory
+	fn split_huge_page<M>(&mut self, index: usize, table_frame: Frame, memory: M) where M : PhysicalMemory {
+		let huge_flags = self.entries[index].flags();
+		let huge_address = self.entries[index].pointed_frame().unwrap().start_address();
+		//a huge page entry keeps its PAT bit in the lowest address bit
+		let pat = huge_address & HUGE_PAGE_PAT != 0;
+		let huge_frame = Frame::containing_address(huge_address & !HUGE_PAGE_PAT);
+		let child_frames = L::entry_frames() / ENTRY_COUNT;
+		let mut child_flags = huge_flags;
+		let mut child_pat = 0;
+		if child_frames == 1 {
+			//a P1 entry uses bit 7 for PAT rather than HUGE_PAGE
+			if !pat {
+				child_flags.remove(HUGE_PAGE);
+			}
+		} else if pat {
+			child_pat = HUGE_PAGE_PAT;
+		}
+
+		{
+			let table = unsafe { &mut *(memory.frame_address(&table_frame) as *mut Table<L::NextLevel>) };
+			for (i, entry) in table.entries.iter_mut().enumerate() {
+				let child = Frame::containing_address(huge_frame.start_address() + i * child_frames * PAGE_SIZE + child_pat);
+				entry.set(child, child_flags);
+			}
+		}
+		//the table entries restrict access from now on, user pages must stay reachable through it
+		self.entries[index].set(table_frame, PRESENT | WRITABLE | (huge_flags & USER_ACCESSIBLE));
+		memory.flush_all();
+	}
+}
+
+impl<L> Index<usize> for Table<L> where L : TableLevel {
+	type Output = Entry;
+
+	fn index(&self, index: usize) -> &Entry {
+		&self.entries[index]
+	}
+}
+
+impl<L> IndexMut<usize> for Table<L> where L : TableLevel {
+	fn index_mut(&mut self, index: usize) -> &mut Entry {
+		&mut self.entries[index]
+	}
+}
+
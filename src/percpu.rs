@@ -0,0 +1,152 @@
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use smp::{self, MAX_CPUS};
+use x86;
+use x86::cpuid;
+
+//Data that belongs to one CPU. IA32_GS_BASE points at the CPU's own PerCpu, whose first field points
+//back at it, so a single load from gs:0 finds it without knowing which CPU we are on.
+
+const IA32_GS_BASE: u32 = 0xC0000101;
+
+pub type ThreadId = usize;
+
+//Only the owning CPU changes these, but any CPU may read them to print statistics
+pub struct CpuStats {
+	pub interrupts: AtomicUsize,
+	pub timer_ticks: AtomicUsize,
+}
+
+impl CpuStats {
+	const fn new() -> CpuStats {
+		CpuStats {
+			interrupts: AtomicUsize::new(0),
+			timer_ticks: AtomicUsize::new(0),
+		}
+	}
+}
+
+//Other CPUs only read the counters, so no ordering is needed
+fn increment(counter: &AtomicUsize) {
+	counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[repr(C)]
+pub struct PerCpu {
+	//must stay the first field - see current()
+	self_pointer: *const PerCpu,
+	cpu_id: usize,
+	apic_id: u8,
+	//set once init_cpu is done - CPUs that never started keep their data zeroed
+	online: AtomicBool,
+	current_thread: Cell<Option<ThreadId>>,
+	interrupt_depth: Cell<usize>,
+	pub stats: CpuStats,
+}
+
+impl PerCpu {
+	const fn new() -> PerCpu {
+		PerCpu {
+			self_pointer: 0 as *const PerCpu,
+			cpu_id: 0,
+			apic_id: 0,
+			online: AtomicBool::new(false),
+			current_thread: Cell::new(None),
+			interrupt_depth: Cell::new(0),
+			stats: CpuStats::new(),
+		}
+	}
+
+	//Index in start order - the boot CPU is 0
+	pub fn cpu_id(&self) -> usize {
+		self.cpu_id
+	}
+
+	pub fn apic_id(&self) -> u8 {
+		self.apic_id
+	}
+
+	pub fn online(&self) -> bool {
+		self.online.load(Ordering::Acquire)
+	}
+
+	pub fn current_thread(&self) -> Option<ThreadId> {
+		self.current_thread.get()
+	}
+
+	pub fn set_current_thread(&self, thread: Option<ThreadId>) {
+		self.current_thread.set(thread);
+	}
+
+	//How many interrupt handlers are running on this CPU, 0 outside of interrupts
+	pub fn interrupt_depth(&self) -> usize {
+		self.interrupt_depth.get()
+	}
+
+	pub fn in_interrupt(&self) -> bool {
+		self.interrupt_depth() > 0
+	}
+
+	pub fn enter_interrupt(&self) {
+		self.interrupt_depth.set(self.interrupt_depth.get() + 1);
+		increment(&self.stats.interrupts);
+	}
+
+	pub fn leave_interrupt(&self) {
+		assert!(self.interrupt_depth.get() > 0, "left an interrupt that was never entered");
+		self.interrupt_depth.set(self.interrupt_depth.get() - 1);
+	}
+
+	pub fn count_timer_tick(&self) {
+		increment(&self.stats.timer_ticks);
+	}
+}
+
+//Each entry is only written by its own CPU through current(), apart from init_cpu setting it up
+static mut CPUS: [PerCpu; MAX_CPUS] = [
+	PerCpu::new(), PerCpu::new(), PerCpu::new(), PerCpu::new(),
+	PerCpu::new(), PerCpu::new(), PerCpu::new(), PerCpu::new(),
+	PerCpu::new(), PerCpu::new(), PerCpu::new(), PerCpu::new(),
+	PerCpu::new(), PerCpu::new(), PerCpu::new(), PerCpu::new(),
+];
+
+//Sets up the per-CPU data of the CPU this runs on and points its GS base at it. Needs nothing else,
+//so it is the first thing every CPU does.
+pub fn init_cpu(cpu: usize) {
+	assert!(cpu < MAX_CPUS, "no per-CPU data for this CPU");
+	unsafe {
+		let data = &mut CPUS[cpu];
+		data.self_pointer = data as *const PerCpu;
+		data.cpu_id = cpu;
+		//available before the local APIC is mapped
+		data.apic_id = cpuid::initial_apic_id();
+		x86::wrmsr(IA32_GS_BASE, data as *const PerCpu as u64);
+		data.online.store(true, Ordering::Release);
+	}
+}
+
+//The data of the CPU this runs on. The reference must not be handed to another CPU.
+pub fn current() -> &'static PerCpu {
+	let pointer: *const PerCpu;
+	unsafe {
+		asm!("mov %gs:0, $0" : "=r" (pointer) ::: "volatile");
+		&*pointer
+	}
+}
+
+//Statistics of another CPU, None if it never came online. The rest of its PerCpu is only for the CPU
+//itself, so it isn't handed out.
+pub fn cpu(cpu: usize) -> Option<&'static CpuStats> {
+	assert!(cpu < MAX_CPUS, "no per-CPU data for this CPU");
+	let data = unsafe { &CPUS[cpu] };
+	if data.online() { Some(&data.stats) } else { None }
+}
+
+pub fn print_cpu_stats() {
+	for id in 0..smp::cpu_indices() {
+		if let Some(stats) = cpu(id) {
+			println!("CPU {}: {} interrupts, {} timer ticks", id,
+				stats.interrupts.load(Ordering::Relaxed), stats.timer_ticks.load(Ordering::Relaxed));
+		}
+	}
+}
@@ -0,0 +1,184 @@
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use acpi;
+use gdt;
+use idt;
+use io::apic;
+use percpu;
+use memory::{self, EntryFlags, PhysicalAddress, PAGE_SIZE, phys_to_virt};
+use x86::{self, cpuid};
+
+//Starts the application processors (every CPU but the boot one). Each gets an INIT IPI and up to two
+//startup IPIs pointing at the real mode trampoline in trampoline.asm, which switches straight to
+//long mode on the kernel's page tables and calls ap_main on a stack of its own.
+// http://wiki.osdev.org/SMP
+
+pub const MAX_CPUS: usize = 16;
+
+//The startup IPI names a 4 KiB page below 1 MiB. init_memory reserves this one before anything can
+//allocate it.
+pub const TRAMPOLINE_ADDRESS: PhysicalAddress = 0x8000;
+
+extern {
+	static trampoline_start: u8;
+	static trampoline_end: u8;
+	static trampoline_page_table: u8;
+	static trampoline_stack: u8;
+	static trampoline_entry: u8;
+	static trampoline_cpu: u8;
+	static trampoline_efer: u8;
+}
+
+//CPUs that have reached ap_main, counting the boot CPU
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+//CPU indices handed out so far, in start order. A CPU that doesn't start keeps its index, so there
+//can be gaps below this.
+static CPU_INDICES: AtomicUsize = AtomicUsize::new(1);
+
+//Index of the AP start_processor is waiting for. The AP claims its start by swapping it for NO_CPU
+//in ap_main - one that turns up after start_processor has given up finds it gone and parks itself.
+const NO_CPU: usize = !0;
+static STARTING_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+pub fn cpu_count() -> usize {
+	CPUS_ONLINE.load(Ordering::SeqCst)
+}
+
+//Every CPU index in use is below this, but not every index below it belongs to a running CPU
+pub fn cpu_indices() -> usize {
+	CPU_INDICES.load(Ordering::SeqCst)
+}
+
+//Starts every enabled processor in the MADT, one after the other
+pub fn start_application_processors() {
+	let madt = match acpi::madt() {
+		Some(madt) => madt,
+		None => {
+			println!("SMP: no MADT, staying on one CPU");
+			return;
+		}
+	};
+	if !apic::apic_enabled() {
+		println!("SMP: no local APIC, staying on one CPU");
+		return;
+	}
+	let boot_apic_id = apic::local_apic().id() as u32;
+
+	unsafe {
+		let size = trampoline_offset(&trampoline_end);
+		assert!(size <= PAGE_SIZE, "trampoline doesn't fit in its page");
+		ptr::copy_nonoverlapping(&trampoline_start as *const u8, phys_to_virt(TRAMPOLINE_ADDRESS) as *mut u8, size);
+
+		//the trampoline loads cr3 while still in real mode, with a 32 bit register
+		let p4_address = x86::cr3();
+		assert!(p4_address < 1 << 32, "the kernel's P4 has to be below 4 GiB for the trampoline");
+		write_trampoline_slot(&trampoline_page_table, p4_address);
+		write_trampoline_slot(&trampoline_entry, ap_main as usize as u64);
+		//the kernel's pages only use NO_EXECUTE if the boot CPU has it, and the APs are the same model
+		let efer = if cpuid::has_feature(cpuid::NO_EXECUTE) { x86::EFER_NXE } else { 0 };
+		write_trampoline_slot(&trampoline_efer, efer);
+	}
+	//the APs are still running from it when they turn paging on
+	memory::identity_map(TRAMPOLINE_ADDRESS, PAGE_SIZE, EntryFlags::empty());
+
+	for processor in madt.processors.iter().filter(|p| p.enabled && p.apic_id != boot_apic_id) {
+		let cpu = cpu_indices();
+		if cpu >= MAX_CPUS {
+			println!("SMP: only {} CPUs are supported", MAX_CPUS);
+			break;
+		}
+		if processor.apic_id > 0xFF {
+			println!("SMP: APIC id {} needs x2APIC mode", processor.apic_id);
+			continue;
+		}
+		CPU_INDICES.store(cpu + 1, Ordering::SeqCst);
+		if !start_processor(cpu, processor.apic_id as u8) {
+			println!("SMP: CPU with APIC id {} did not start", processor.apic_id);
+		}
+	}
+
+	//every AP is either running on its own stack or parked by start_processor
+	memory::identity_unmap(TRAMPOLINE_ADDRESS, PAGE_SIZE);
+	println!("SMP: {} CPUs online", cpu_count());
+}
+
+//Offset of a label in trampoline.asm from the start of the trampoline
+fn trampoline_offset(label: &u8) -> usize {
+	label as *const u8 as usize - unsafe { &trampoline_start as *const u8 as usize }
+}
+
+//Writes one of the data slots at the end of the trampoline copy
+unsafe fn write_trampoline_slot(label: &u8, value: u64) {
+	ptr::write_volatile(phys_to_virt(TRAMPOLINE_ADDRESS + trampoline_offset(label)) as *mut u64, value);
+}
+
+//INIT-SIPI-SIPI - returns once the AP is online, or false if it never claims its start. An AP that
+//doesn't start in time gets INIT again, which parks it until the next startup IPI. Its stack and index
+//are never handed out again in case it got as far as using them.
+fn start_processor(cpu: usize, apic_id: u8) -> bool {
+	//stacks are never freed, so a late AP can't end up sharing this one
+	let stack = memory::alloc_stack().expect("out of kernel stacks");
+	unsafe {
+		write_trampoline_slot(&trampoline_stack, stack.top as u64);
+		write_trampoline_slot(&trampoline_cpu, cpu as u64);
+	}
+	let online = cpu_count();
+	STARTING_CPU.store(cpu, Ordering::SeqCst);
+
+	let local_apic = apic::local_apic();
+	local_apic.send_init(apic_id);
+	apic::pit_sleep(10_000);
+	//the second startup IPI is only needed if the first one got lost
+	for _ in 0..2 {
+		local_apic.send_startup(apic_id, TRAMPOLINE_ADDRESS / PAGE_SIZE);
+		apic::pit_sleep(200);
+		if start_claimed(cpu) {
+			break;
+		}
+	}
+	//the AP may still be on its way - give it 100ms
+	for _ in 0..100 {
+		if start_claimed(cpu) {
+			break;
+		}
+		apic::pit_sleep(1000);
+	}
+	//taking the start back fails if the AP claimed it after the last look
+	if STARTING_CPU.compare_and_swap(cpu, NO_CPU, Ordering::SeqCst) == cpu {
+		local_apic.send_init(apic_id);
+		return false;
+	}
+
+	//the AP is on its own stack now and doesn't fail from here
+	while cpu_count() == online {}
+	true
+}
+
+fn start_claimed(cpu: usize) -> bool {
+	STARTING_CPU.load(Ordering::SeqCst) != cpu
+}
+
+//Where the trampoline leaves every AP, with interrupts disabled and on the stack from start_processor
+extern "C" fn ap_main(cpu: usize) -> ! {
+	if STARTING_CPU.compare_and_swap(cpu, NO_CPU, Ordering::SeqCst) != cpu {
+		//start_processor gave up on us and is about to send INIT
+		park();
+	}
+	percpu::init_cpu(cpu);
+	x86::enable_write_protect_bit();
+	let double_fault_stack = memory::alloc_stack().expect("out of kernel stacks");
+	gdt::init_cpu_gdt(cpu, double_fault_stack.top as u64);
+	idt::load_idt();
+	apic::init_ap_apic();
+
+	let this_cpu = percpu::current();
+	println!("CPU {} online (APIC id {})", this_cpu.cpu_id(), this_cpu.apic_id());
+	CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+
+	unsafe { asm!("sti" :::: "volatile"); }
+	loop { unsafe { asm!("hlt" :::: "volatile"); } }
+}
+
+fn park() -> ! {
+	loop { unsafe { asm!("cli; hlt" :::: "volatile"); } }
+}
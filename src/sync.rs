@@ -0,0 +1,77 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use x86;
+
+//A spinlock that keeps interrupts disabled while it is held. Anything an interrupt handler locks has
+//to use one: with a plain spin::Mutex an IRQ arriving while normal code holds the lock spins forever
+//on the same CPU.
+pub struct IrqMutex<T> {
+	inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+	//always Some until drop, which has to release the lock before enabling interrupts again
+	guard: Option<MutexGuard<'a, T>>,
+	saved_flags: u64,
+}
+
+impl<T> IrqMutex<T> {
+	pub const fn new(data: T) -> IrqMutex<T> {
+		IrqMutex {
+			inner: Mutex::new(data),
+		}
+	}
+
+	//Saves RFLAGS and disables interrupts before spinning - dropping the guard restores them
+	pub fn lock(&self) -> IrqMutexGuard<T> {
+		let saved_flags = x86::rflags();
+		unsafe { x86::disable_interrupts(); }
+		IrqMutexGuard {
+			guard: Some(self.inner.lock()),
+			saved_flags: saved_flags,
+		}
+	}
+
+	//Leaves the interrupt flag alone if the lock is taken
+	pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+		let saved_flags = x86::rflags();
+		unsafe { x86::disable_interrupts(); }
+		match self.inner.try_lock() {
+			Some(guard) => Some(IrqMutexGuard {
+				guard: Some(guard),
+				saved_flags: saved_flags,
+			}),
+			None => {
+				restore_interrupts(saved_flags);
+				None
+			}
+		}
+	}
+}
+
+fn restore_interrupts(saved_flags: u64) {
+	if saved_flags & x86::RFLAGS_INTERRUPT_FLAG != 0 {
+		unsafe { x86::enable_interrupts(); }
+	}
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		self.guard.as_ref().unwrap()
+	}
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.guard.as_mut().unwrap()
+	}
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+	fn drop(&mut self) {
+		self.guard = None;
+		restore_interrupts(self.saved_flags);
+	}
+}
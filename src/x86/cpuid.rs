@@ -0,0 +1,184 @@
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+//What the CPU supports, so the rest of the kernel can decide at runtime instead of assuming.
+//boot.asm has already checked that cpuid and long mode are there.
+// http://wiki.osdev.org/CPUID
+
+const LEAF_VENDOR: u32 = 0;
+const LEAF_FEATURES: u32 = 1;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const LEAF_BRAND_STRING: u32 = 0x8000_0002; // and the two after it
+
+bitflags! {
+	pub flags CpuFeatures: u64 {
+		//leaf 1, edx
+		const TSC = 1 << 0,
+		const MSR = 1 << 1,
+		const PAE = 1 << 2,
+		const APIC = 1 << 3,
+		const PGE = 1 << 4,
+		const SSE = 1 << 5,
+		const SSE2 = 1 << 6,
+		//leaf 1, ecx
+		const SSE3 = 1 << 7,
+		const SSSE3 = 1 << 8,
+		const SSE4_1 = 1 << 9,
+		const SSE4_2 = 1 << 10,
+		const X2APIC = 1 << 11,
+		const TSC_DEADLINE = 1 << 12,
+		const XSAVE = 1 << 13,
+		const AVX = 1 << 14,
+		const RDRAND = 1 << 15,
+		//leaf 0x80000001, edx
+		const NO_EXECUTE = 1 << 16,
+		const HUGE_PAGES_1GIB = 1 << 17,
+		const LONG_MODE = 1 << 18,
+	}
+}
+
+//(cpuid register, bit in that register, feature)
+const FEATURES_EDX: [(u32, CpuFeatures); 7] = [
+	(4, TSC), (5, MSR), (6, PAE), (9, APIC), (13, PGE), (25, SSE), (26, SSE2),
+];
+const FEATURES_ECX: [(u32, CpuFeatures); 9] = [
+	(0, SSE3), (9, SSSE3), (19, SSE4_1), (20, SSE4_2), (21, X2APIC), (24, TSC_DEADLINE),
+	(26, XSAVE), (28, AVX), (30, RDRAND),
+];
+const EXTENDED_FEATURES_EDX: [(u32, CpuFeatures); 3] = [
+	(20, NO_EXECUTE), (26, HUGE_PAGES_1GIB), (29, LONG_MODE),
+];
+
+//Executes cpuid for leaf (subleaf 0) and returns eax, ebx, ecx and edx
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+	cpuid_subleaf(leaf, 0)
+}
+
+pub fn cpuid_subleaf(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+	let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+	unsafe {
+		asm!("cpuid" : "={eax}" (eax), "={ebx}" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+			: "{eax}" (leaf), "{ecx}" (subleaf) :: "volatile");
+	}
+	(eax, ebx, ecx, edx)
+}
+
+fn collect(register: u32, bits: &[(u32, CpuFeatures)]) -> CpuFeatures {
+	bits.iter()
+		.filter(|&&(bit, _)| register & (1 << bit) != 0)
+		.fold(CpuFeatures::empty(), |features, &(_, feature)| features | feature)
+}
+
+fn max_extended_leaf() -> u32 {
+	cpuid(LEAF_EXTENDED_MAX).0
+}
+
+//The features as first read, with FEATURES_READ set - the APs are assumed to match the boot CPU
+static FEATURES: AtomicUsize = AtomicUsize::new(0);
+const FEATURES_READ: usize = 1 << 63;
+
+fn read_features() -> CpuFeatures {
+	let (_, _, ecx, edx) = cpuid(LEAF_FEATURES);
+	let mut features = collect(edx, &FEATURES_EDX) | collect(ecx, &FEATURES_ECX);
+	if max_extended_leaf() >= LEAF_EXTENDED_FEATURES {
+		let (_, _, _, extended_edx) = cpuid(LEAF_EXTENDED_FEATURES);
+		features = features | collect(extended_edx, &EXTENDED_FEATURES_EDX);
+	}
+	features
+}
+
+//Only asks the CPU the first time, after that the answer comes from FEATURES
+pub fn features() -> CpuFeatures {
+	let cached = FEATURES.load(Ordering::Relaxed);
+	if cached & FEATURES_READ != 0 {
+		return CpuFeatures::from_bits_truncate(cached as u64);
+	}
+	let features = read_features();
+	FEATURES.store(features.bits() as usize | FEATURES_READ, Ordering::Relaxed);
+	features
+}
+
+pub fn has_feature(feature: CpuFeatures) -> bool {
+	features().contains(feature)
+}
+
+//APIC id of the CPU this runs on as it was at reset - readable before the local APIC is mapped
+pub fn initial_apic_id() -> u8 {
+	let (_, ebx, _, _) = cpuid(LEAF_FEATURES);
+	(ebx >> 24) as u8
+}
+
+pub struct CpuInfo {
+	vendor: [u8; 12],
+	brand: [u8; 48],
+	pub family: u32,
+	pub model: u32,
+	pub stepping: u32,
+	pub features: CpuFeatures,
+}
+
+impl CpuInfo {
+	pub fn read() -> CpuInfo {
+		let mut info = CpuInfo {
+			vendor: [0; 12],
+			brand: [0; 48],
+			family: 0,
+			model: 0,
+			stepping: 0,
+			features: features(),
+		};
+
+		//the vendor string is spread over ebx, edx, ecx in that order
+		let (_, ebx, ecx, edx) = cpuid(LEAF_VENDOR);
+		for (i, register) in [ebx, edx, ecx].iter().enumerate() {
+			write_register(&mut info.vendor[i * 4..i * 4 + 4], *register);
+		}
+
+		//the extended family and model only count for some base families
+		let (eax, _, _, _) = cpuid(LEAF_FEATURES);
+		let base_family = (eax >> 8) & 0xF;
+		let base_model = (eax >> 4) & 0xF;
+		info.stepping = eax & 0xF;
+		info.family = if base_family == 0xF { base_family + ((eax >> 20) & 0xFF) } else { base_family };
+		info.model = if base_family == 0x6 || base_family == 0xF {
+			(((eax >> 16) & 0xF) << 4) | base_model
+		} else {
+			base_model
+		};
+
+		if max_extended_leaf() >= LEAF_BRAND_STRING + 2 {
+			for leaf in 0..3 {
+				let (eax, ebx, ecx, edx) = cpuid(LEAF_BRAND_STRING + leaf);
+				for (i, register) in [eax, ebx, ecx, edx].iter().enumerate() {
+					let start = leaf as usize * 16 + i * 4;
+					write_register(&mut info.brand[start..start + 4], *register);
+				}
+			}
+		}
+		info
+	}
+
+	pub fn vendor(&self) -> &str {
+		str::from_utf8(&self.vendor).unwrap_or("unknown")
+	}
+
+	//Empty on CPUs without a brand string
+	pub fn brand(&self) -> &str {
+		let end = self.brand.iter().position(|&byte| byte == 0).unwrap_or(self.brand.len());
+		str::from_utf8(&self.brand[..end]).unwrap_or("").trim()
+	}
+}
+
+fn write_register(bytes: &mut [u8], register: u32) {
+	for (i, byte) in bytes.iter_mut().enumerate() {
+		*byte = (register >> (i * 8)) as u8;
+	}
+}
+
+pub fn print_cpu_info() {
+	let info = CpuInfo::read();
+	println!("CPU: {} {} (family {:#x}, model {:#x}, stepping {})",
+		info.vendor(), info.brand(), info.family, info.model, info.stepping);
+	println!("CPU features: {:?}", info.features);
+}
@@ -1,3 +1,4 @@
+pub mod cpuid;
 
 //Reads the CR0 register
 pub unsafe fn cr0() -> u64 {
@@ -11,6 +12,13 @@ pub unsafe fn cr0_write(val : u64) {
 	asm!("mov $0, %cr0" :: "r" (val) : "memory");
 }
 
+//Reads the CR2 register - holds the address that caused the last page fault
+pub unsafe fn cr2() -> u64 {
+	let ret: u64;
+	asm!("mov %cr2, $0" : "=r" (ret));
+	ret
+}
+
 //Reads the CR3 register - causes a general protection fault if not in kernel mode
 pub unsafe fn cr3() -> u64 {
 	let ret: u64;
@@ -34,6 +42,8 @@ pub unsafe fn flush_tlb_all() {
 }
 
 const IA32_EFER: u32 = 0xc0000080;
+//Turns the NO_EXECUTE page table flag on - the flag is reserved without it
+pub const EFER_NXE: u64 = 1 << 11;
 
 //Write the 64 bits MSR register
 pub unsafe fn wrmsr(msr: u32, value: u64) {
@@ -56,11 +66,46 @@ pub fn enable_write_protect_bit() {
 	unsafe { cr0_write(cr0() | wp_bit) };
 }
 
-//enable the NO_EXECUTE page table flag
-pub fn enable_nxe_bit() {
-	let nxe_bit = 1 << 11;
+//enable the NO_EXECUTE page table flag - returns false if the CPU doesn't have it
+pub fn enable_nxe_bit() -> bool {
+	if !cpuid::has_feature(cpuid::NO_EXECUTE) {
+		return false;
+	}
 	unsafe {
 		let efer = rdmsr(IA32_EFER);
-		wrmsr(IA32_EFER, efer | nxe_bit);
+		wrmsr(IA32_EFER, efer | EFER_NXE);
+	}
+	true
+}
+
+pub const RFLAGS_INTERRUPT_FLAG: u64 = 1 << 9;
+
+//Reads the RFLAGS register
+pub fn rflags() -> u64 {
+	let ret: u64;
+	unsafe { asm!("pushfq; popq $0" : "=r" (ret) :: "memory" : "volatile"); }
+	ret
+}
+
+pub fn interrupts_enabled() -> bool {
+	rflags() & RFLAGS_INTERRUPT_FLAG != 0
+}
+
+pub unsafe fn disable_interrupts() {
+	asm!("cli" :::: "volatile");
+}
+
+pub unsafe fn enable_interrupts() {
+	asm!("sti" :::: "volatile");
+}
+
+//Runs f with interrupts disabled and enables them again afterwards if they were enabled before
+pub fn without_interrupts<F, R>(f: F) -> R where F : FnOnce() -> R {
+	let enabled = interrupts_enabled();
+	unsafe { disable_interrupts(); }
+	let ret = f();
+	if enabled {
+		unsafe { enable_interrupts(); }
 	}
+	ret
 }